@@ -1,19 +1,39 @@
 use {
-    criterion::{criterion_group, criterion_main, Criterion},
+    criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput},
     hex::FromHex,
     ws_gonzale::dataframe::mask_payload,
 };
 
+/// Payload sizes from 16 B to 16 MB
+const PAYLOAD_SIZES: [usize; 7] = [16, 256, 4 << 10, 64 << 10, 1 << 20, 4 << 20, 16 << 20];
+
+/// The byte by byte unmasking we had before, to compare with
+fn mask_payload_byte_by_byte(data: &mut [u8], mask: [u8; 4]) {
+    for i in 0..data.len() {
+        data[i] ^= mask[i % 4];
+    }
+}
 fn unmasking_payload_mut(c: &mut Criterion) {
     let mut group = c.benchmark_group("Unmasking payload");
     group.bench_function("super_mask", |b| {
         let hex_dump = "81 9c 9d f4 e4 dc cf 9b 87 b7 bd 9d 90 fc ea 9d 90 b4 bd bc b0 91 d1 c1 c4 8b f8 96 b7 b3 fe 9f 81 a8".replace(" ", "");
         let mut binary_vec: Vec<u8> = Vec::from_hex(hex_dump).expect("Invalid Hex String");
-        let slice = &mut &mut *binary_vec;
         b.iter(|| {
-            let _ = mask_payload(slice, [0, 1, 1, 0]);
+            let _ = mask_payload(&mut &mut *binary_vec, [0, 1, 1, 0]);
         })
     });
+    for size in PAYLOAD_SIZES.iter() {
+        let mut payload = vec![7u8; *size];
+        group.throughput(Throughput::Bytes(*size as u64));
+        group.bench_with_input(BenchmarkId::new("mask_payload", size), size, |b, _| {
+            b.iter(|| {
+                let _ = mask_payload(&mut &mut *payload, [90, 212, 118, 181]);
+            })
+        });
+        group.bench_with_input(BenchmarkId::new("byte_by_byte", size), size, |b, _| {
+            b.iter(|| mask_payload_byte_by_byte(&mut payload, [90, 212, 118, 181]))
+        });
+    }
     group.finish();
 }
 fn bench_dataframe(c: &mut Criterion) {
@@ -27,6 +47,32 @@ fn bench_dataframe(c: &mut Criterion) {
             dataframe.get_message().unwrap()
         })
     });
+    group.bench_function("dataframe_ref", |b| {
+        let buffer: Vec<u8> = vec![
+            129, 139, 90, 212, 118, 181, 18, 177, 26, 217, 53, 244, 33, 218, 40, 184, 18,
+        ];
+        // The buffer isn't changed so every iteration unmasks the same masked payload
+        b.iter(|| {
+            let dataframe = ws_gonzale::DataframeRef::parse(&buffer).unwrap().unwrap();
+            dataframe.get_message().unwrap()
+        })
+    });
+    group.bench_function("dataframe_ref_in_place", |b| {
+        let buffer: Vec<u8> = vec![
+            129, 139, 90, 212, 118, 181, 18, 177, 26, 217, 53, 244, 33, 218, 40, 184, 18,
+        ];
+        // Every iteration gets a fresh masked copy, the copy isn't measured
+        b.iter_batched_ref(
+            || buffer.clone(),
+            |buffer| {
+                let dataframe = ws_gonzale::DataframeRef::parse_mut(buffer)
+                    .unwrap()
+                    .unwrap();
+                dataframe.get_payload().len()
+            },
+            BatchSize::SmallInput,
+        )
+    });
     group.finish();
 }
 
@@ -1,4 +1,3 @@
-use futures::AsyncWriteExt;
 use {
     crate::lib::server::{ServerData, ServerMessage},
     std::sync::atomic::{AtomicUsize, Ordering},
@@ -6,8 +5,8 @@ use {
         async_channel::Sender,
         async_std::{sync::Arc, task, task::JoinHandle},
         async_trait::async_trait,
-        futures::StreamExt,
-        Channels, HTTPMethod, Message, Request, Server, WsClientHook, WsConnection, WsEvents,
+        Channels, CloseFrame, DeflateConfig, HTTPMethod, HandshakeDecision, Message, Response,
+        Router, Server, WsClientHook, WsConfig,
     },
 };
 
@@ -42,7 +41,7 @@ impl WsClientHook for ConnectionEvents {
         Ok(())
     }
 
-    async fn after_drop(&self) -> Result<(), ()> {
+    async fn after_drop(&self, _close_frame: &CloseFrame) -> Result<(), ()> {
         let _ = self
             .server_sender
             .send(ServerMessage::ClientDisconnected(self.get_id()))
@@ -65,72 +64,41 @@ impl WsClientHook for ConnectionEvents {
 pub fn connections(server_data: Arc<ServerData>) -> JoinHandle<Result<(), std::io::Error>> {
     task::spawn(async move {
         // TODO: Extract this from a Config struct that's built with .dotenv or something
-        let server = Server::new("127.0.0.1:8080".parse().unwrap()).await?;
-        let mut incoming = server.incoming();
-        while let Some(Ok(mut connection)) = incoming.next().await {
-            let time = std::time::Instant::now();
-            let server_sender = server_data.get_channel_sender();
-            let post_sender = server_data.get_channel_sender();
-            task::spawn(async move {
-                // We extracted out TcpStream read from WsConnection so we can be more flexible in the implementation
-                let request = Request::read_from_stream(&mut connection).await?;
-                match request.get_endpoint().get_method() {
-                    HTTPMethod::GET
-                        if request
-                            .get_headers()
-                            .get("Upgrade")
-                            .map(|s| s.to_string().to_ascii_lowercase())
-                            == Some(String::from("websocket")) =>
+        let config = WsConfig::new().deflate(DeflateConfig::default());
+        let server = Server::with_config("127.0.0.1:8080".parse().unwrap(), config).await?;
+        let chat_sender = server_data.get_channel_sender();
+        let notify_sender = server_data.get_channel_sender();
+        let router = Router::new()
+            // Every WebSocket connection on /chat gets its own ConnectionEvents hook
+            .websocket("/chat", move |_| {
+                HandshakeDecision::accept(ConnectionEvents::new(chat_sender.clone()))
+            })
+            .route(HTTPMethod::GET, "/metrics", move |_, _| {
+                let server_data = Arc::clone(&server_data);
+                async move {
+                    let connections = server_data.get_nr_of_connections().await;
+                    Response::new(200, "OK").with_body(&format!("connections {}", connections))
+                }
+            })
+            // Simple POST message to all clients on the server
+            .route(HTTPMethod::POST, "/notify", move |request, _| {
+                let notify_sender = notify_sender.clone();
+                async move {
+                    let send_data = match request.get_body() {
+                        Some(body) => body.get_body().to_string(),
+                        None => return Response::new(400, "Bad Request"),
+                    };
+                    if let Err(err) = notify_sender
+                        .send(ServerMessage::ClientMessage(Message::Text(
+                            send_data.clone(),
+                        )))
+                        .await
                     {
-                        let default_str = String::new();
-                        let key = request
-                            .get_headers()
-                            .get("Sec-WebSocket-Key")
-                            .unwrap_or(&default_str);
-
-                        // Upgrade to WS connection because the run cycle and reading dataframes assumes a WSConnection
-                        let ws_connection = WsConnection::upgrade(connection, key).await?;
-
-                        // Run cycle
-                        let ws_events =
-                            WsEvents::new(ws_connection, ConnectionEvents::new(server_sender))
-                                .await?;
-                        let _ = ws_events.run().await?;
-                    }
-                    HTTPMethod::GET => {
-                        let response = format!(
-                            "HTTP/1.0 200 OK\r\nRequest-Duration-In-Microseconds: {microseconds}\r\nContent-Length: 2\r\n\r\nOK\r\n",
-                            microseconds = time.elapsed().as_micros()
-                        );
-                        let _ = connection.write_all(response.as_bytes()).await;
+                        println!("Failed to send: {}", err);
                     }
-                    // Simple POST message to all clients on the server
-                    HTTPMethod::POST => {
-                        if let Some(body) = request.get_body() {
-                            let send_data = body.get_body();
-                            if let Err(err) = post_sender
-                                .send(ServerMessage::ClientMessage(Message::Text(
-                                    send_data.to_string(),
-                                )))
-                                .await
-                            {
-                                println!("Failed to send: {}", err);
-                            }
-                            let response = format!(
-                                "HTTP/1.0 200 OK\r\nRequest-Duration-In-Microseconds: {microseconds}\r\nContent-Length: {send_data_length}\r\n\r\n{send_data}\r\n",
-                                send_data = send_data,
-                                send_data_length = send_data.len(),
-                                microseconds = time.elapsed().as_micros()
-                            );
-                            let _ = connection.write_all(response.as_bytes()).await;
-                        }
-                    }
-                    _ => {}
+                    Response::new(200, "OK").with_body(&send_data)
                 }
-                Ok::<_, std::io::Error>(())
             });
-        }
-
-        Ok::<(), std::io::Error>(())
+        server.serve(router).await
     })
 }
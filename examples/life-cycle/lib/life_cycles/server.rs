@@ -19,8 +19,8 @@ pub fn server(server_data: Arc<ServerData>) -> JoinHandle<Result<(), std::io::Er
             match server_message {
                 // The client passed the Message packet to the server
                 ServerMessage::ClientMessage(message) => {
-                    // Ooh, the client sent a Text frame.. how exciting; send it to the other clients on the server
-                    if let Message::Text(_) = message {
+                    // Ooh, the client sent a Text or Binary frame.. how exciting; send it to the other clients on the server
+                    if let Message::Text(_) | Message::Binary(_) = message {
                         //<editor-fold desc="One way, wait for each client before moving on to the next one">
                         /*                        let buffer = get_buffer(message);
                         let connections = server_data.connections.lock().await;
@@ -7,6 +7,7 @@ pub use {
     },
 };
 
+#[allow(clippy::enum_variant_names)]
 pub enum ServerMessage {
     ClientMessage(Message),
     ClientJoined((u32, Channels)),
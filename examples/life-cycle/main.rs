@@ -1,3 +1,4 @@
+#![allow(special_module_name)]
 mod lib;
 use lib::start_server;
 use ws_gonzale::{async_std::task, AsyncResult};
@@ -0,0 +1,163 @@
+use {
+    crate::{
+        deflate::DeflateConfig,
+        handshake::{OriginPolicy, DEFAULT_MAX_BODY_SIZE, DEFAULT_MAX_HEADER_SIZE},
+    },
+    std::time::Duration,
+};
+
+/// Largest frame payload we accept by default, 16 MiB
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 16 << 20;
+/// Largest (possibly fragmented) message payload we accept by default, 64 MiB
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 64 << 20;
+/// How many bytes of a request or of frames we read from the tcp_stream at a time
+pub(crate) const READ_BUFFER_SIZE: usize = 8192;
+
+/// Pings the client every `interval` and drops the connection once `max_missed_pongs` pings in a row went unanswered
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Heartbeat {
+    pub interval: Duration,
+    pub max_missed_pongs: u32,
+}
+
+/// Settings for a [`WsConnection`](`crate::connection::WsConnection`) and the [`WsEvents`](`crate::connection::WsEvents`) running it.
+#[derive(Clone, Debug)]
+pub struct WsConfig {
+    heartbeat: Option<Heartbeat>,
+    close_timeout: Duration,
+    strict: bool,
+    max_frame_size: usize,
+    max_message_size: usize,
+    deflate: Option<DeflateConfig>,
+    streaming: bool,
+    subprotocols: Vec<String>,
+    origin_policy: OriginPolicy,
+    max_header_size: usize,
+    max_body_size: usize,
+}
+impl Default for WsConfig {
+    fn default() -> Self {
+        Self {
+            heartbeat: None,
+            close_timeout: Duration::from_secs(5),
+            strict: true,
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+            deflate: None,
+            streaming: false,
+            subprotocols: Vec::new(),
+            origin_policy: OriginPolicy::Any,
+            max_header_size: DEFAULT_MAX_HEADER_SIZE,
+            max_body_size: DEFAULT_MAX_BODY_SIZE,
+        }
+    }
+}
+impl WsConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Turns on the server side heartbeat, it's off by default.
+    /// The connection is closed with [`CloseCode::GoingAway`](`crate::message::CloseCode::GoingAway`) after `max_missed_pongs` unanswered pings,
+    /// with 0 the client is pinged but never dropped.
+    pub fn heartbeat(mut self, interval: Duration, max_missed_pongs: u32) -> Self {
+        self.heartbeat = Some(Heartbeat {
+            interval,
+            max_missed_pongs,
+        });
+        self
+    }
+    pub fn get_heartbeat(&self) -> Option<Heartbeat> {
+        self.heartbeat
+    }
+    /// How long we wait for the close frame to be written before shutting down the tcp_stream, defaults to 5 seconds
+    pub fn close_timeout(mut self, close_timeout: Duration) -> Self {
+        self.close_timeout = close_timeout;
+        self
+    }
+    pub fn get_close_timeout(&self) -> Duration {
+        self.close_timeout
+    }
+    /// Strict mode closes the connection with [`CloseCode::ProtocolError`](`crate::message::CloseCode::ProtocolError`)
+    /// on frames breaking RFC 6455, e.g. unmasked frames or unknown opcodes. It's on by default, pass `false` to be lenient.
+    pub fn strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+    pub fn is_strict(&self) -> bool {
+        self.strict
+    }
+    /// Frames with a larger payload close the connection with [`CloseCode::MessageTooBig`](`crate::message::CloseCode::MessageTooBig`)
+    /// before anything is allocated for them, defaults to [`DEFAULT_MAX_FRAME_SIZE`]
+    pub fn max_frame_size(mut self, max_frame_size: usize) -> Self {
+        self.max_frame_size = max_frame_size;
+        self
+    }
+    pub fn get_max_frame_size(&self) -> usize {
+        self.max_frame_size
+    }
+    /// Same as [`WsConfig::max_frame_size`] but for all fragments of a message together, defaults to [`DEFAULT_MAX_MESSAGE_SIZE`]
+    pub fn max_message_size(mut self, max_message_size: usize) -> Self {
+        self.max_message_size = max_message_size;
+        self
+    }
+    pub fn get_max_message_size(&self) -> usize {
+        self.max_message_size
+    }
+    /// Accepts permessage-deflate when the client offers it, it's off by default.
+    /// Only connections upgraded with [`WsConnection::upgrade_from_request`](`crate::connection::WsConnection::upgrade_from_request`) can negotiate it.
+    pub fn deflate(mut self, deflate: DeflateConfig) -> Self {
+        self.deflate = Some(deflate);
+        self
+    }
+    pub fn get_deflate(&self) -> Option<DeflateConfig> {
+        self.deflate
+    }
+    /// Passes text and binary messages to [`WsClientHook::on_message_stream`](`crate::connection::WsClientHook::on_message_stream`)
+    /// frame by frame instead of buffering them, it's off by default. Streamed messages are still limited by
+    /// [`WsConfig::max_message_size`], the stream ends with an error and the connection is closed once they go over it.
+    pub fn streaming(mut self, streaming: bool) -> Self {
+        self.streaming = streaming;
+        self
+    }
+    pub fn is_streaming(&self) -> bool {
+        self.streaming
+    }
+    /// The `Sec-WebSocket-Protocol`s we speak in order of preference, e.g. `chat.v2` before `chat.v1`.
+    /// A client offering only others is rejected, one offering none gets a connection without a subprotocol.
+    pub fn subprotocols(mut self, subprotocols: &[&str]) -> Self {
+        self.subprotocols = subprotocols.iter().map(|s| s.to_string()).collect();
+        self
+    }
+    pub fn get_subprotocols(&self) -> &[String] {
+        &self.subprotocols
+    }
+    /// Handshakes from an `Origin` the policy doesn't allow are rejected with `403 Forbidden`, every origin is allowed by default.
+    /// Only [`WsConnection::upgrade_from_request`](`crate::connection::WsConnection::upgrade_from_request`) and the upgrades taking
+    /// a [`Request`](`crate::handshake::Request`) can check it, the deprecated key-only upgrades reject every handshake unless it's [`OriginPolicy::Any`].
+    pub fn origin_policy(mut self, origin_policy: OriginPolicy) -> Self {
+        self.origin_policy = origin_policy;
+        self
+    }
+    pub fn get_origin_policy(&self) -> &OriginPolicy {
+        &self.origin_policy
+    }
+    /// Requests read with [`Request::read_from_stream_with_config`](`crate::handshake::Request::read_from_stream_with_config`)
+    /// fail with [`WsGonzaleError::HeadersTooLarge`](`crate::WsGonzaleError::HeadersTooLarge`) once the request line and headers
+    /// go over this, defaults to [`DEFAULT_MAX_HEADER_SIZE`]
+    pub fn max_header_size(mut self, max_header_size: usize) -> Self {
+        self.max_header_size = max_header_size;
+        self
+    }
+    pub fn get_max_header_size(&self) -> usize {
+        self.max_header_size
+    }
+    /// Same as [`WsConfig::max_header_size`] but for the body, which fails with
+    /// [`WsGonzaleError::RequestTooLarge`](`crate::WsGonzaleError::RequestTooLarge`), defaults to [`DEFAULT_MAX_BODY_SIZE`]
+    pub fn max_body_size(mut self, max_body_size: usize) -> Self {
+        self.max_body_size = max_body_size;
+        self
+    }
+    pub fn get_max_body_size(&self) -> usize {
+        self.max_body_size
+    }
+}
@@ -1,12 +1,33 @@
 use {
-    crate::{dataframe, handshake, message::Message, Channel, WsGonzaleResult},
-    async_channel::Sender,
+    crate::{
+        config::{Heartbeat, WsConfig, READ_BUFFER_SIZE},
+        dataframe::Opcode,
+        deflate::PerMessageDeflate,
+        extension::{Extension, Extensions, Frame},
+        handshake::{self, HandshakeDecision, HandshakeError, OriginPolicy, Request},
+        message::{CloseCode, CloseFrame, Message},
+        protocol::{Utf8Validator, WsProtocol},
+        stream::MessageStream,
+        Channel, WsGonzaleError, WsGonzaleResult,
+    },
+    async_channel::{Receiver, Sender},
     async_net::TcpStream,
-    async_std::task,
+    async_std::{
+        future::timeout,
+        sync::Arc,
+        task::{self, JoinHandle},
+    },
     async_trait::async_trait,
-    futures::{AsyncReadExt, AsyncWriteExt},
+    futures::{
+        future::{self, Either},
+        AsyncReadExt, AsyncWriteExt,
+    },
+    std::sync::atomic::{AtomicBool, AtomicU32, Ordering},
 };
 
+/// How many frames of a streamed message we read ahead of the [`MessageStream`]
+const STREAM_BUFFER_FRAMES: usize = 4;
+
 /// Channels is sent to the struct implementing [`WsClientHook`] so they can use it to send to the mpmc channel or directly to the [`TcpStream`]
 pub type Channels = (Sender<Vec<u8>>, TcpStream);
 
@@ -16,28 +37,104 @@ pub trait WsClientHook {
     /// Once the user has been upgraded from a regular HTTP GET request to a WS connection that's kept open.
     async fn after_handshake(&mut self) -> Result<(), ()>;
     /// Once the connection has dropped, this is async so we can wait for this because drop doesn't have an async implementation yet/ever?
-    async fn after_drop(&self) -> Result<(), ()>;
+    /// The [`CloseFrame`] is the one the client sent, [`CloseCode::Abnormal`] means the connection dropped without a close handshake.
+    async fn after_drop(&self, close_frame: &CloseFrame) -> Result<(), ()>;
     /// When we've interpreted a complete WS frame packet
     async fn on_message(&self, message: &Message) -> Result<(), ()>;
+    /// With [`WsConfig::streaming`] turned on text and binary messages come here as their frames arrive instead of [`WsClientHook::on_message`].
+    /// The rest of the message is read and thrown away if the stream is dropped early.
+    /// By default the whole stream is read and passed on to [`WsClientHook::on_message`], a stream over
+    /// [`WsConfig::max_message_size`] ends with [`WsGonzaleError::MessageTooLarge`] before it's all buffered.
+    async fn on_message_stream(&self, mut stream: MessageStream) -> Result<(), ()> {
+        let mut payload = Vec::new();
+        stream.read_to_end(&mut payload).await.map_err(|_| ())?;
+        let message = if stream.is_text() {
+            Message::Text(String::from_utf8(payload).map_err(|_| ())?)
+        } else {
+            Message::Binary(payload)
+        };
+        self.on_message(&message).await
+    }
     /// This our multi producer / multi consumer channel. (Could be done with a mpsc channel as well since we only ever use this once in the code?)
     fn set_channels(&mut self, ws_writer: Channels);
 }
-#[derive(Clone)]
 /// Our WSConnection after it's been upgraded from a TCPStream
-pub struct WsConnection(TcpStream);
+pub struct WsConnection {
+    tcp_stream: TcpStream,
+    /// Everything but the reading and writing
+    protocol: WsProtocol,
+    /// The `Sec-WebSocket-Protocol` we picked in the handshake
+    subprotocol: Option<String>,
+}
 impl WsConnection {
     pub fn get_tcp_stream(&self) -> TcpStream {
-        self.0.clone()
+        self.tcp_stream.clone()
+    }
+    pub fn get_config(&self) -> &WsConfig {
+        self.protocol.get_config()
+    }
+    /// The subprotocol negotiated with [`WsConfig::subprotocols`], `None` if there is none
+    pub fn get_subprotocol(&self) -> Option<&str> {
+        self.subprotocol.as_deref()
+    }
+    /// Feeds bytes that were already read from the tcp_stream to the frame decoder, e.g. [`Request::get_remaining`]
+    /// when upgrading with [`WsConnection::upgrade`]. [`WsConnection::upgrade_from_request`] does this on its own.
+    pub fn receive(&mut self, bytes: &[u8]) {
+        self.protocol.receive(bytes);
+    }
+}
+/// What the read loop in [`WsEvents::run`] gets from the [`WsConnection`]
+enum Incoming {
+    Message(Message),
+    /// The first frame of a message that's streamed to [`WsClientHook::on_message_stream`]
+    Stream(Frame),
+}
+/// What [`WsConnection::incoming_frame`] was woken up by
+enum Wakeup {
+    Read(std::io::Result<usize>),
+    /// A frame the hook sent through the [`Channels`]
+    Frame(Vec<u8>),
+    /// A message we send ourselves
+    Message(Message),
+    /// Can't happen while the [`WsEvents`] holds on to the senders
+    Closed,
+}
+/// Shared between the read loop in [`WsEvents::run`] and the heartbeat task
+#[derive(Default)]
+struct HeartbeatState {
+    missed_pongs: AtomicU32,
+    is_closed: AtomicBool,
+}
+impl HeartbeatState {
+    /// Any pong counts as an answer to our pings
+    fn received(&self, message: &Message) {
+        if let Message::Pong(_) = message {
+            self.missed_pongs.store(0, Ordering::SeqCst);
+        }
     }
 }
+/// Where the frames we send go, they are queued on the [`WsProtocol`] while we read and then handed to the writer
+struct Outgoing {
+    /// Frames the hook sent through the [`Channels`]
+    frames: Receiver<Vec<u8>>,
+    /// Messages we send ourselves, e.g. the pings of the heartbeat
+    messages: Receiver<Message>,
+    /// What [`WsProtocol::take_outgoing`] encoded, the writer only writes it and stops at an empty buffer
+    writer: Sender<Vec<u8>>,
+}
 /// Handles WebSocket incoming data frames and sends back to [`WsClientHook`] methods.
 pub struct WsEvents {
     ws_connection: WsConnection,
     /// Our multi producer / multi consumer channel channels we are creating upon creating the connection
     channel: Channel<Vec<u8>>,
+    /// Sends to [`Outgoing::messages`]
+    messages: Sender<Message>,
+    outgoing: Outgoing,
     /// Client hooks; we could do this in the life cycle; but I wanted the library to be as easily implemented as possible for end users.
     /// So we'll have to deal with wrapping this behind a pointer (Boxing it here) since we don't know the size of the struct developers will implement WsClientHook on.
     client_hook: Box<dyn WsClientHook + Send + Sync>,
+    /// Writes the bytes the [`WsProtocol`] encoded until the connection is shut down
+    writer: Option<JoinHandle<()>>,
 }
 impl WsEvents {
     /// Upgrades the TcpStream to a WsConnection that's basically a handshake between a client and server
@@ -46,100 +143,445 @@ impl WsEvents {
         ws_connection: WsConnection,
         client_hook: impl WsClientHook + Send + Sync + 'static,
     ) -> WsGonzaleResult<WsEvents> {
+        WsEvents::with_boxed_hook(ws_connection, Box::new(client_hook)).await
+    }
+    /// Same as [`WsEvents::new`] with a hook that's already boxed, e.g. one made by a [`Router`](`crate::router::Router`)
+    pub(crate) async fn with_boxed_hook(
+        ws_connection: WsConnection,
+        client_hook: Box<dyn WsClientHook + Send + Sync>,
+    ) -> WsGonzaleResult<WsEvents> {
+        let channel: Channel<Vec<u8>> = async_channel::unbounded();
+        let (messages, message_receiver) = async_channel::unbounded();
+        let (writer_sender, writer_receiver) = async_channel::unbounded();
         let mut ws_events = WsEvents {
             ws_connection,
-            channel: async_channel::unbounded(),
-            client_hook: Box::new(client_hook),
+            outgoing: Outgoing {
+                frames: channel.1.clone(),
+                messages: message_receiver,
+                writer: writer_sender,
+            },
+            channel,
+            messages,
+            client_hook,
+            writer: None,
         };
 
-        let _ = ws_events.setup_listeners().await;
+        let _ = ws_events.setup_listeners(writer_receiver).await;
 
         Ok(ws_events)
     }
     /// Clones the Sender channel and returns it. This is so we can have multiple places where we can send to this channel if desired.
-    /// Setup a reader of the multi producer and write to the underlying tcp_stream of our guest client.
-    async fn setup_listeners(&mut self) -> WsGonzaleResult<()> {
+    /// The frames sent to it go through the [`WsProtocol`] and the writer writes the bytes it encoded to the underlying tcp_stream of our guest client.
+    async fn setup_listeners(&mut self, writer_receiver: Receiver<Vec<u8>>) -> WsGonzaleResult<()> {
         // Send the WsWriter to this stream to the client hook
         self.client_hook
             .set_channels((self.channel.0.clone(), self.ws_connection.get_tcp_stream()));
 
-        // Clone this because we are moving it into a new future which could be on another thread.
-        let channel_reader = self.channel.1.clone();
-
-        // Same idea here; we need to clone this so we can keep reading from tcp_stream in incoming_message
+        // We need to clone this so we can keep reading from tcp_stream in incoming_message
         let mut tcp_stream_writer = self.ws_connection.get_tcp_stream();
 
-        task::spawn(async move {
-            while let Ok(buffer) = channel_reader.recv().await {
-                let _ = tcp_stream_writer.write_all(&buffer).await;
+        // Nothing is queued after a close frame, the empty buffer sent on shutdown ends this
+        self.writer = Some(task::spawn(async move {
+            while let Ok(buffer) = writer_receiver.recv().await {
+                if buffer.is_empty() || tcp_stream_writer.write_all(&buffer).await.is_err() {
+                    break;
+                }
             }
-            Ok::<(), std::io::Error>(())
-        });
+        }));
 
         let _ = self.client_hook.after_handshake().await;
         Ok(())
     }
+    /// Pings the client on an interval and closes the connection with [`CloseCode::GoingAway`] when too many pongs are missing,
+    /// both go through the [`WsProtocol`] so it knows we are closing. The tcp_stream is shut down once the close timeout
+    /// has passed, which in turn makes the read loop in [`WsEvents::run`] end.
+    fn spawn_heartbeat(&self, heartbeat: Heartbeat, state: Arc<HeartbeatState>) {
+        let sender = self.messages.clone();
+        let tcp_stream = self.ws_connection.get_tcp_stream();
+        let close_timeout = self.ws_connection.get_config().get_close_timeout();
+        task::spawn(async move {
+            loop {
+                task::sleep(heartbeat.interval).await;
+                if state.is_closed.load(Ordering::SeqCst) {
+                    break;
+                }
+                // With 0 we keep pinging but never drop the connection
+                let missed_pongs = state.missed_pongs.fetch_add(1, Ordering::SeqCst);
+                if heartbeat.max_missed_pongs > 0 && missed_pongs >= heartbeat.max_missed_pongs {
+                    let close_frame = CloseFrame::new(CloseCode::GoingAway, "Missed pongs");
+                    let _ = sender.send(Message::Close(Some(close_frame))).await;
+                    task::sleep(close_timeout).await;
+                    let _ = tcp_stream.shutdown(std::net::Shutdown::Both);
+                    break;
+                }
+                if sender.send(Message::Ping(Vec::new())).await.is_err() {
+                    break;
+                }
+            }
+        });
+    }
     /// This is the run which handles the WsEvents lifecycle.
     /// Here we take full ownership because when we are done; we should drop the connection.
     pub async fn run(mut self) -> WsGonzaleResult<()> {
-        while let Ok(message) = self.ws_connection.incoming_message().await {
-            if Message::Close == message {
+        let heartbeat_state = Arc::new(HeartbeatState::default());
+        if let Some(heartbeat) = self.ws_connection.get_config().get_heartbeat() {
+            self.spawn_heartbeat(heartbeat, Arc::clone(&heartbeat_state));
+        }
+        loop {
+            let message = match self.ws_connection.incoming(&self.outgoing).await {
+                Ok(Incoming::Message(message)) => Ok(Some(message)),
+                Ok(Incoming::Stream(frame)) => self.stream_message(frame, &heartbeat_state).await,
+                Err(error) => Err(error),
+            };
+            // Protocol errors are answered with a close frame, anything else means the connection is gone
+            if let Err(error) = message {
+                self.ws_connection.protocol.fail(error);
+            }
+            // Pongs, the echo of a close frame or the close frame of a protocol error
+            if self.ws_connection.flush(&self.outgoing).await.is_err() {
+                break;
+            }
+            let message = match message {
+                Ok(Some(message)) => message,
+                Ok(None) => continue,
+                Err(_) => break,
+            };
+            if let Message::Close(_) = message {
                 break;
-            } else {
-                // pass events to client hook
-                let _ = self.client_hook.on_message(&message).await;
             }
+            heartbeat_state.received(&message);
+            // pass events to client hook
+            let _ = self.client_hook.on_message(&message).await;
         }
+        heartbeat_state.is_closed.store(true, Ordering::SeqCst);
+        let is_closing = self.ws_connection.protocol.is_closing();
+        self.shutdown(is_closing).await;
         Ok(())
     }
+    /// Passes a streamed message to [`WsClientHook::on_message_stream`] while we keep reading its frames.
+    /// A close frame in the middle of the message is returned so the read loop can handle it.
+    async fn stream_message(
+        &mut self,
+        first_frame: Frame,
+        heartbeat_state: &HeartbeatState,
+    ) -> WsGonzaleResult<Option<Message>> {
+        let (sender, receiver) = async_channel::bounded(STREAM_BUFFER_FRAMES);
+        let stream = MessageStream::new(first_frame.opcode == Opcode::Text as u8, receiver);
+        let (ws_connection, client_hook, outgoing) =
+            (&mut self.ws_connection, &*self.client_hook, &self.outgoing);
+        let feed = async {
+            let result = feed_stream(
+                ws_connection,
+                client_hook,
+                outgoing,
+                heartbeat_state,
+                first_frame,
+                &sender,
+            )
+            .await;
+            // The stream ends with an error unless the final frame was read
+            match &result {
+                Ok(None) => {}
+                Ok(Some(_)) => {
+                    let _ = sender.send(Err(WsGonzaleError::ConnectionClosed)).await;
+                }
+                Err(error) => {
+                    let _ = sender.send(Err(*error)).await;
+                }
+            }
+            drop(sender);
+            result
+        };
+        let (_, result) = futures::join!(client_hook.on_message_stream(stream), feed);
+        result
+    }
+    /// Gives the writer a bounded time to flush the close frame if we are closing and then shuts down the tcp_stream
+    async fn shutdown(&mut self, is_closing: bool) {
+        // The writer still writes what was handed to it before
+        let _ = self.outgoing.writer.send(Vec::new()).await;
+        match self.writer.take() {
+            Some(writer) if is_closing => {
+                let close_timeout = self.ws_connection.get_config().get_close_timeout();
+                let _ = timeout(close_timeout, writer).await;
+            }
+            _ => {}
+        }
+        let _ = self
+            .ws_connection
+            .get_tcp_stream()
+            .shutdown(std::net::Shutdown::Both);
+    }
+}
+
+/// Sends the payloads of a streamed message to `sender` until the final frame, control frames in between are handled as usual
+async fn feed_stream(
+    ws_connection: &mut WsConnection,
+    client_hook: &(dyn WsClientHook + Send + Sync),
+    outgoing: &Outgoing,
+    heartbeat_state: &HeartbeatState,
+    first_frame: Frame,
+    sender: &Sender<WsGonzaleResult<Vec<u8>>>,
+) -> WsGonzaleResult<Option<Message>> {
+    let is_text = first_frame.opcode == Opcode::Text as u8;
+    let mut utf8_validator = Utf8Validator::default();
+    let max_message_size = ws_connection.get_config().get_max_message_size();
+    let mut message_size = 0;
+    let mut frame = first_frame;
+    let mut is_first = true;
+    loop {
+        if Opcode::is_control(frame.opcode) {
+            match ws_connection.protocol.push_frame(frame)? {
+                Some(message @ Message::Close(_)) => return Ok(Some(message)),
+                Some(message) => {
+                    ws_connection.flush(outgoing).await?;
+                    heartbeat_state.received(&message);
+                    let _ = client_hook.on_message(&message).await;
+                }
+                None => {}
+            }
+        } else {
+            if !is_first && frame.opcode != Opcode::Continuation as u8 {
+                return Err(WsGonzaleError::ExpectedContinuation);
+            }
+            is_first = false;
+            message_size += frame.payload.len();
+            if message_size > max_message_size {
+                return Err(WsGonzaleError::MessageTooLarge);
+            }
+            if is_text {
+                utf8_validator.push(&frame.payload)?;
+                if frame.fin {
+                    utf8_validator.finish()?;
+                }
+            }
+            let is_fin = frame.fin;
+            // Fails if the stream was dropped, the rest of the message is still read
+            let _ = sender.send(Ok(frame.payload)).await;
+            if is_fin {
+                return Ok(None);
+            }
+        }
+        frame = ws_connection.incoming_frame(outgoing).await?;
+    }
 }
 
 impl WsConnection {
     /// Upgrades the TcpStream to a WsConnection that's basically a handshake between a client and server
     /// and the connection is kept open.
+    /// Only the key is checked, use [`WsConnection::upgrade_from_request`] to validate the rest of the request.
+    #[deprecated(note = "only checks the key, use `WsConnection::upgrade_from_request` instead")]
     pub async fn upgrade(tcp_stream: TcpStream, accept_key: &str) -> WsGonzaleResult<WsConnection> {
-        let mut connection = WsConnection(tcp_stream);
+        #[allow(deprecated)]
+        WsConnection::upgrade_with_config(tcp_stream, accept_key, WsConfig::default()).await
+    }
+    /// Same as [`WsConnection::upgrade`] but with a [`WsConfig`] for this connection.
+    /// The `Origin` can't be checked without the request, so any [`WsConfig::origin_policy`] but [`OriginPolicy::Any`] rejects the handshake.
+    #[deprecated(note = "only checks the key, use `WsConnection::upgrade_from_request` instead")]
+    pub async fn upgrade_with_config(
+        mut tcp_stream: TcpStream,
+        accept_key: &str,
+        config: WsConfig,
+    ) -> WsGonzaleResult<WsConnection> {
+        if !matches!(config.get_origin_policy(), OriginPolicy::Any) {
+            log::warn!(
+                "Rejected WebSocket handshake without a request to check the origin policy against"
+            );
+            let error = HandshakeError::ForbiddenOrigin;
+            handshake::reject(error, &mut tcp_stream).await?;
+            return Err(WsGonzaleError::InvalidHandshake(error));
+        }
+        let mut connection = WsConnection::new(tcp_stream, config, Extensions::default());
         // Before returning the WsConnection; make sure the handshake is done.
-        connection
-            .handshake(accept_key)
-            .await
-            .map_err(|_| std::io::Error::from(std::io::ErrorKind::Interrupted))?;
+        handshake::handshake_with_headers(accept_key, &[], &mut connection.tcp_stream).await?;
 
         Ok(connection)
     }
-    async fn handshake(&mut self, key: &str) -> Result<(), std::io::Error> {
-        handshake::handshake(key, &mut self.0).await
+    /// Upgrades with the headers of the client's [`Request`], this also negotiates the extensions turned on in the [`WsConfig`].
+    /// A request that isn't a valid WebSocket handshake is answered with an HTTP error response instead.
+    pub async fn upgrade_from_request(
+        tcp_stream: TcpStream,
+        request: &Request,
+        config: WsConfig,
+    ) -> WsGonzaleResult<WsConnection> {
+        let (connection, _) =
+            WsConnection::upgrade_with_callback(tcp_stream, request, config, |_| {
+                HandshakeDecision::accept(())
+            })
+            .await?;
+        Ok(connection)
     }
-    /// Read incoming data packets from tcp stream
-    async fn incoming_message(&mut self) -> WsGonzaleResult<Message> {
-        let mut buffer: [u8; 2] = [0; 2];
-
-        // Do a peek-ahead so we can utilize the read_exact of the full payload and then use From<&[u8]> for Dataframe
-        match self.0.peek(&mut buffer).await {
-            // Connection was aborted
-            Ok(s) if s == 0 => {
-                return Err(std::io::Error::from(std::io::ErrorKind::ConnectionAborted))?;
-            }
-            // fin(126) + opcode for close(8), see rfc protocol.. just ignore the reason.
-            Ok(_) if buffer.len() > 0 && buffer[0] == 136 => return Ok(Message::Close),
-            Ok(_) => {}
-            // Upon error, return early
-            Err(err) => return Err(err)?,
+    /// Same as [`WsConnection::upgrade_from_request`] but `callback` decides whether a valid handshake is upgraded
+    /// with extra response headers or rejected with an HTTP response, e.g. when the client isn't logged in.
+    /// The data it accepted the request with is returned with the connection.
+    pub async fn upgrade_with_callback<T>(
+        tcp_stream: TcpStream,
+        request: &Request,
+        config: WsConfig,
+        callback: impl FnOnce(&Request) -> HandshakeDecision<T>,
+    ) -> WsGonzaleResult<(WsConnection, T)> {
+        WsConnection::upgrade_with_subprotocol_callback(
+            tcp_stream,
+            request,
+            config,
+            |request, _| callback(request),
+        )
+        .await
+    }
+    /// Same as [`WsConnection::upgrade_with_callback`] but `callback` also gets the subprotocol we picked
+    pub(crate) async fn upgrade_with_subprotocol_callback<T>(
+        tcp_stream: TcpStream,
+        request: &Request,
+        config: WsConfig,
+        callback: impl FnOnce(&Request, Option<&str>) -> HandshakeDecision<T>,
+    ) -> WsGonzaleResult<(WsConnection, T)> {
+        let mut extensions: Vec<Box<dyn Extension>> = Vec::new();
+        if let Some(deflate) = config.get_deflate() {
+            let deflate = PerMessageDeflate::new(deflate)
+                .with_max_message_size(config.get_max_message_size());
+            extensions.push(Box::new(deflate));
+        }
+        WsConnection::upgrade_with(tcp_stream, request, config, extensions, callback).await
+    }
+    /// Same as [`WsConnection::upgrade_from_request`] but with our own [`Extension`]s in order of preference,
+    /// the ones the client offered are negotiated.
+    pub async fn upgrade_with_extensions(
+        tcp_stream: TcpStream,
+        request: &Request,
+        config: WsConfig,
+        extensions: Vec<Box<dyn Extension>>,
+    ) -> WsGonzaleResult<WsConnection> {
+        let (connection, _) =
+            WsConnection::upgrade_with(tcp_stream, request, config, extensions, |_, _| {
+                HandshakeDecision::accept(())
+            })
+            .await?;
+        Ok(connection)
+    }
+    async fn upgrade_with<T>(
+        mut tcp_stream: TcpStream,
+        request: &Request,
+        config: WsConfig,
+        extensions: Vec<Box<dyn Extension>>,
+        callback: impl FnOnce(&Request, Option<&str>) -> HandshakeDecision<T>,
+    ) -> WsGonzaleResult<(WsConnection, T)> {
+        let handshake = request.validate_handshake().and_then(|accept_key| {
+            let origin = request.get_headers().get("Origin");
+            match request.validate_origin(config.get_origin_policy()) {
+                Ok(()) => log::debug!("Allowed WebSocket handshake from origin {:?}", origin),
+                Err(error) => {
+                    log::warn!("Rejected WebSocket handshake from origin {:?}", origin);
+                    return Err(error);
+                }
+            }
+            let subprotocol = request.negotiate_subprotocol(config.get_subprotocols())?;
+            Ok((accept_key, subprotocol, request.get_extension_offers()?))
+        });
+        let (accept_key, subprotocol, offers) = match handshake {
+            Ok(handshake) => handshake,
+            Err(error) => {
+                handshake::reject(error, &mut tcp_stream).await?;
+                return Err(WsGonzaleError::InvalidHandshake(error));
+            }
         };
+        let (mut headers, data) = match callback(request, subprotocol.as_deref()) {
+            HandshakeDecision::Accept { headers, data } => (headers, data),
+            HandshakeDecision::Reject(response) => {
+                handshake::respond(&response, &mut tcp_stream).await?;
+                return Err(WsGonzaleError::HandshakeRejected(response.get_status()));
+            }
+        };
+        // The offers we decline are left out of the response
+        let (extensions, responses) = Extensions::negotiate(extensions, &offers);
+        if !responses.is_empty() {
+            let responses: Vec<String> = responses.iter().map(|r| r.to_string()).collect();
+            headers.push(("Sec-WebSocket-Extensions".to_string(), responses.join(", ")));
+        }
+        if let Some(subprotocol) = &subprotocol {
+            headers.push(("Sec-WebSocket-Protocol".to_string(), subprotocol.clone()));
+        }
+        let mut connection = WsConnection::new(tcp_stream, config, extensions);
+        connection.subprotocol = subprotocol;
+        // A client can send its first frames right after the request
+        connection.receive(request.get_remaining());
+        handshake::handshake_with_headers(accept_key, &headers, &mut connection.tcp_stream).await?;
 
-        // Just peek ahead for the largest data package 127 in size. That's 2 (two first frames including fin, rsv1-3, mask and payload_length) + 8 (u64 size in bytes) + 4 (masking_key) = 14
-        let mut peeked_buff: [u8; 14] = [0; 14];
-        self.0.peek(&mut peeked_buff).await?;
-
-        let dataframe = dataframe::DataframeBuilder::new(peeked_buff.to_vec())?;
-        let mut payload: Vec<u8> = vec![0; dataframe.get_full_frame_length() as usize];
-
-        self.0.read_exact(&mut payload).await?;
-
-        let dataframe = dataframe::DataframeBuilder::new(payload)?;
-        let message = dataframe.get_message().unwrap_or(Message::Unknown);
-
-        Ok(message)
+        Ok((connection, data))
+    }
+    fn new(tcp_stream: TcpStream, config: WsConfig, extensions: Extensions) -> WsConnection {
+        WsConnection {
+            tcp_stream,
+            protocol: WsProtocol::with_extensions(config, extensions),
+            subprotocol: None,
+        }
+    }
+    /// Read incoming data packets from tcp stream until we have a complete message.
+    /// Fragmented messages are buffered, control frames in between them are returned as they come.
+    /// With [`WsConfig::streaming`] text and binary messages are returned as soon as their first frame arrives.
+    async fn incoming(&mut self, outgoing: &Outgoing) -> WsGonzaleResult<Incoming> {
+        let is_streaming = self.get_config().is_streaming();
+        loop {
+            let frame = self.incoming_frame(outgoing).await?;
+            let is_data = matches!(Opcode::from(frame.opcode), Opcode::Text | Opcode::Binary);
+            if is_streaming && is_data && !self.protocol.is_fragmented() {
+                return Ok(Incoming::Stream(frame));
+            }
+            if let Some(message) = self.protocol.push_frame(frame)? {
+                return Ok(Incoming::Message(message));
+            }
+        }
+    }
+    /// Read one frame, bytes are read in chunks and fed to the [`WsProtocol`] until it has a complete frame.
+    /// The frames and messages sent while we wait are queued on the protocol and flushed.
+    async fn incoming_frame(&mut self, outgoing: &Outgoing) -> WsGonzaleResult<Frame> {
+        let mut buffer = [0u8; READ_BUFFER_SIZE];
+        loop {
+            if let Some(frame) = self.protocol.next_frame()? {
+                return Ok(frame);
+            }
+            // What was sent while we were busy goes first, so a client that keeps sending can't hold it back
+            while let Ok(frame) = outgoing.frames.try_recv() {
+                let _ = self.protocol.send_buffer(frame);
+            }
+            while let Ok(message) = outgoing.messages.try_recv() {
+                let _ = self.protocol.send(message);
+            }
+            self.flush(outgoing).await?;
+            let wakeup = {
+                let read = self.tcp_stream.read(&mut buffer);
+                let frame = outgoing.frames.recv();
+                let message = outgoing.messages.recv();
+                futures::pin_mut!(read, frame, message);
+                match future::select(read, future::select(frame, message)).await {
+                    Either::Left((read, _)) => Wakeup::Read(read),
+                    Either::Right((Either::Left((Ok(frame), _)), _)) => Wakeup::Frame(frame),
+                    Either::Right((Either::Right((Ok(message), _)), _)) => Wakeup::Message(message),
+                    Either::Right(_) => Wakeup::Closed,
+                }
+            };
+            // What was sent is flushed on the next round
+            match wakeup {
+                Wakeup::Read(read) => match read? {
+                    // Connection was aborted
+                    0 => return Err(std::io::Error::from(std::io::ErrorKind::ConnectionAborted))?,
+                    read => self.protocol.receive(&buffer[..read]),
+                },
+                Wakeup::Frame(frame) => {
+                    let _ = self.protocol.send_buffer(frame);
+                }
+                Wakeup::Message(message) => {
+                    let _ = self.protocol.send(message);
+                }
+                Wakeup::Closed => return Err(WsGonzaleError::ConnectionClosed),
+            }
+        }
+    }
+    /// Hands the bytes the [`WsProtocol`] encoded to the writer, they go through a channel so they aren't interleaved with other writes
+    async fn flush(&mut self, outgoing: &Outgoing) -> WsGonzaleResult<()> {
+        let buffer = self.protocol.take_outgoing()?;
+        if !buffer.is_empty() {
+            let _ = outgoing.writer.send(buffer).await;
+        }
+        Ok(())
     }
 }
 
@@ -148,6 +590,9 @@ impl WsConnection {
 impl Drop for WsEvents {
     fn drop(&mut self) {
         // Block this thread until notified since Drop doesn't support async
-        let _ = task::block_on(self.client_hook.after_drop());
+        let _ = task::block_on(
+            self.client_hook
+                .after_drop(self.ws_connection.protocol.get_close_frame()),
+        );
     }
 }
@@ -1,39 +1,118 @@
-use crate::{message::Message, WsGonzaleError, WsGonzaleResult};
+use {
+    crate::{
+        decoder,
+        message::{CloseFrame, Message},
+        WsGonzaleError, WsGonzaleResult,
+    },
+    std::borrow::Cow,
+};
 
-/// Converts a [`Message`] to a `Vec<u8>`
+/// Converts a [`Message`] to a `Vec<u8>` with a server side (unmasked) [`DataframeEncoder`]
 #[inline(always)]
 pub fn get_buffer(message: Message) -> Vec<u8> {
-    let mut buffer: Vec<u8> = Vec::new();
-    buffer.push(129);
-    let s = match message {
-        Message::Text(s) => s,
-        _ => "".to_owned(),
-    };
-    match s.len() as u64 {
-        size @ 0..=125 => {
-            buffer.push(size as u8);
+    DataframeEncoder::new().encode(&message)
+}
+/// Encodes a [`Message`] into a frame, servers send unmasked frames and clients masked frames
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DataframeEncoder {
+    masking_key: Option<[u8; 4]>,
+}
+impl DataframeEncoder {
+    /// Encoder for the server side, the frames aren't masked
+    pub fn new() -> DataframeEncoder {
+        DataframeEncoder::default()
+    }
+    /// Encoder for the client side, every frame is masked with `masking_key`
+    pub fn with_masking_key(masking_key: [u8; 4]) -> DataframeEncoder {
+        DataframeEncoder {
+            masking_key: Some(masking_key),
+        }
+    }
+    /// Encodes a complete message in a single frame
+    pub fn encode(&self, message: &Message) -> Vec<u8> {
+        match message {
+            Message::Binary(payload) => self.encode_frame(true, Opcode::Binary as u8, payload),
+            Message::Text(s) => self.encode_frame(true, Opcode::Text as u8, s.as_bytes()),
+            Message::Ping(payload) => self.encode_frame(true, Opcode::Ping as u8, payload),
+            Message::Pong(payload) => self.encode_frame(true, Opcode::Pong as u8, payload),
+            Message::Close(close_frame) => {
+                let payload = close_frame
+                    .as_ref()
+                    .map(|close_frame| close_frame.get_payload())
+                    .unwrap_or_default();
+                self.encode_frame(true, Opcode::Close as u8, &payload)
+            }
+            Message::Unknown => self.encode_frame(true, Opcode::Text as u8, &[]),
         }
-        size if size > u32::MAX as u64 => {
-            let bytes: [u8; 8] = (size as u64).to_be_bytes();
-            buffer.push(127);
-            buffer.extend_from_slice(&bytes);
+    }
+    /// Encodes one frame, `fin` is false for every fragment except the last one
+    pub fn encode_frame(&self, fin: bool, opcode: u8, payload: &[u8]) -> Vec<u8> {
+        self.encode_frame_with_rsv(fin, 0, opcode, payload)
+    }
+    /// Same as [`DataframeEncoder::encode_frame`] but with RSV bits set by a negotiated extension, e.g. RSV1 for a compressed message
+    pub fn encode_frame_with_rsv(&self, fin: bool, rsv: u8, opcode: u8, payload: &[u8]) -> Vec<u8> {
+        // 2 (first two bytes) + 8 (u64 size in bytes) + 4 (masking_key) = 14 at most
+        let mut buffer: Vec<u8> = Vec::with_capacity(14 + payload.len());
+        let fin = if fin { frame_positions::FIN } else { 0 };
+        let rsv = rsv & (frame_positions::RSV1 | frame_positions::RSV2 | frame_positions::RSV3);
+        buffer.push(fin | rsv | (opcode & frame_positions::MASK_OPCODE));
+
+        let is_mask = if self.masking_key.is_some() {
+            frame_positions::IS_MASK
+        } else {
+            0
+        };
+        match payload.len() as u64 {
+            size @ 0..=125 => {
+                buffer.push(is_mask | size as u8);
+            }
+            size @ 126..=0xFFFF => {
+                buffer.push(is_mask | 126);
+                buffer.extend_from_slice(&(size as u16).to_be_bytes());
+            }
+            size => {
+                buffer.push(is_mask | 127);
+                buffer.extend_from_slice(&size.to_be_bytes());
+            }
         }
-        size if size <= u32::MAX as u64 => {
-            let bytes: [u8; 2] = (size as u16).to_be_bytes();
-            buffer.push(126);
-            buffer.extend_from_slice(&bytes);
+
+        match self.masking_key {
+            Some(masking_key) => {
+                buffer.extend_from_slice(&masking_key);
+                let payload_start = buffer.len();
+                buffer.extend_from_slice(payload);
+                mask_payload(&mut &mut buffer[payload_start..], masking_key);
+            }
+            None => buffer.extend_from_slice(payload),
         }
-        _ => panic!("Don't know what to do here..."),
+        buffer
     }
-    buffer.extend_from_slice(s.as_bytes());
+}
+/// Whether a buffer from [`get_buffer`] is a close frame, nothing may be sent after it
+#[inline(always)]
+pub fn is_close_buffer(buffer: &[u8]) -> bool {
     buffer
+        .first()
+        .map(|frame| Opcode::from(frame & frame_positions::MASK_OPCODE) == Opcode::Close)
+        .unwrap_or(false)
 }
 #[inline(always)]
-/// This masks the payload byte by byte and does a bitwise exclusive on index % 4 of mask
+/// This masks the payload with a bitwise exclusive or on index % 4 of mask, masking twice unmasks it.
+/// It works on 8 bytes at a time and the bytes left over one at a time, which is bit-identical to going byte by byte.
 pub fn mask_payload<'a, 'b>(incoming: &'a mut &'b mut [u8], mask: [u8; 4]) -> &'a [u8] {
     let data: &'b mut [u8] = std::mem::take(incoming);
-    for i in 0..data.len() {
-        data[i] ^= mask[i % 4];
+    let mask_word = u64::from_ne_bytes([
+        mask[0], mask[1], mask[2], mask[3], mask[0], mask[1], mask[2], mask[3],
+    ]);
+    let mut words = data.chunks_exact_mut(8);
+    for word in &mut words {
+        let mut bytes = [0u8; 8];
+        bytes.copy_from_slice(word);
+        word.copy_from_slice(&(u64::from_ne_bytes(bytes) ^ mask_word).to_ne_bytes());
+    }
+    // A multiple of 8 bytes has been masked so the rest starts at mask[0] again
+    for (i, byte) in words.into_remainder().iter_mut().enumerate() {
+        *byte ^= mask[i % 4];
     }
     data
 }
@@ -53,10 +132,12 @@ pub struct Dataframe {
     masking_key: [u8; 4],
     payload: Vec<u8>,
 }
-#[derive(PartialEq)]
-enum Opcode {
+/// Opcode in the first byte of a [`Dataframe`], see RFC 6455 section 5.2
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Opcode {
     Continuation = 0,
     Text = 1,
+    Binary = 2,
     Close = 8,
     Ping = 9,
     Pong = 10,
@@ -67,6 +148,7 @@ impl From<u8> for Opcode {
         match v {
             0 => Opcode::Continuation,
             1 => Opcode::Text,
+            2 => Opcode::Binary,
             8 => Opcode::Close,
             9 => Opcode::Ping,
             10 => Opcode::Pong,
@@ -74,6 +156,14 @@ impl From<u8> for Opcode {
         }
     }
 }
+impl Opcode {
+    /// Control frames (close, ping and pong) have the most significant opcode bit set
+    /// and are allowed to be interleaved with the fragments of a message
+    #[inline(always)]
+    pub fn is_control(opcode: u8) -> bool {
+        opcode & 0b00001000 == 0b00001000
+    }
+}
 
 #[derive(Debug)]
 enum ExtraSize {
@@ -81,7 +171,8 @@ enum ExtraSize {
     Two,
     Eight,
 }
-mod frame_positions {
+/// Bit masks of the fields in the first two bytes of a frame
+pub mod frame_positions {
     // Frame one
     pub const FIN: u8 = 128;
     pub const RSV1: u8 = 64;
@@ -93,34 +184,35 @@ mod frame_positions {
     pub const MASK_PAYLOAD_LENGTH: u8 = 0b01111111;
 }
 impl DataframeBuilder {
+    #[allow(clippy::new_ret_no_self)]
     pub fn new(buffer: Vec<u8>) -> WsGonzaleResult<Dataframe> {
         DataframeBuilder(buffer).get_dataframe()
     }
     #[inline(always)]
     fn is_fin(&self) -> bool {
         self.0
-            .get(0)
+            .first()
             .map(|frame| (frame & frame_positions::FIN) == frame_positions::FIN)
             .unwrap_or(false)
     }
     #[inline(always)]
     fn is_rsv1(&self) -> bool {
         self.0
-            .get(0)
+            .first()
             .map(|frame| (frame & frame_positions::RSV1) == frame_positions::RSV1)
             .unwrap_or(false)
     }
     #[inline(always)]
     fn is_rsv2(&self) -> bool {
         self.0
-            .get(0)
+            .first()
             .map(|frame| (frame & frame_positions::RSV2) == frame_positions::RSV2)
             .unwrap_or(false)
     }
     #[inline(always)]
     fn is_rsv3(&self) -> bool {
         self.0
-            .get(0)
+            .first()
             .map(|frame| (frame & frame_positions::RSV3) == frame_positions::RSV3)
             .unwrap_or(false)
     }
@@ -129,7 +221,7 @@ impl DataframeBuilder {
     fn get_opcode(&self) -> u8 {
         // default to close
         self.0
-            .get(0)
+            .first()
             .map(|frame| frame & frame_positions::MASK_OPCODE)
             .unwrap_or(8)
     }
@@ -175,7 +267,7 @@ impl DataframeBuilder {
                 {
                     u64::from_be_bytes([
                         *first, *second, *third, *fourth, *fifth, *sixth, *seventh, *eighth,
-                    ]) as u64
+                    ])
                 }
                 _ => return Err(WsGonzaleError::Unknown),
             },
@@ -186,11 +278,13 @@ impl DataframeBuilder {
 
     fn get_payload_start_pos(&self) -> WsGonzaleResult<u64> {
         let result = match self.get_extra_payload_bytes()? {
-            ExtraSize::Zero(_) => 6,
-            ExtraSize::Two => 8,
-            ExtraSize::Eight => 14,
+            ExtraSize::Zero(_) => 2,
+            ExtraSize::Two => 4,
+            ExtraSize::Eight => 10,
         };
-        Ok(result)
+        // The masking key sits between the payload length and the payload
+        let masking_key_length = if self.is_mask() { 4 } else { 0 };
+        Ok(result + masking_key_length)
     }
     pub fn get_full_frame_length(&self) -> WsGonzaleResult<u64> {
         let size = self.get_payload_start_pos()? + self.get_payload_length()?;
@@ -226,16 +320,13 @@ impl DataframeBuilder {
         let masking_key = self.get_masking_key()?;
         let payload_length = self.get_payload_length()? as usize;
 
-        if Opcode::from(self.get_opcode()) == Opcode::Close {
-            // TODO: Add support for reason message for closing
-            return Err(WsGonzaleError::ConnectionClosed);
-        }
         if start_payload > self.0.len() {
             return Err(WsGonzaleError::InvalidPayload);
         }
-        // Remove first {start_payload}:th bytes from dataframe payload
+        // Remove first {start_payload}:th bytes from dataframe payload, the buffer is reused for the payload
         self.0.drain(0..start_payload);
-        let mut data = self.0.into_iter().take(payload_length).collect::<Vec<u8>>();
+        self.0.truncate(payload_length);
+        let mut data = self.0;
         if is_mask {
             mask_payload(&mut &mut *data, masking_key);
         }
@@ -258,19 +349,69 @@ impl DataframeBuilder {
         Ok(result)
     }
 }
+/// Interprets a complete payload as a [`Message`], the opcode is the one from the first frame of the message
+#[inline(always)]
+pub fn get_message_from_payload(opcode: u8, payload: Vec<u8>) -> WsGonzaleResult<Message> {
+    let result = match Opcode::from(opcode) {
+        Opcode::Text => {
+            Message::Text(String::from_utf8(payload).map_err(|_| WsGonzaleError::InvalidUtf8)?)
+        }
+        Opcode::Binary => Message::Binary(payload),
+        Opcode::Close => Message::Close(CloseFrame::from_payload(&payload)?),
+        Opcode::Ping => Message::Ping(payload),
+        Opcode::Pong => Message::Pong(payload),
+        _ => Message::Unknown,
+    };
+    Ok(result)
+}
+/// The checks of [`Dataframe::validate_with_rsv`] and [`DataframeRef::validate_with_rsv`]
+fn validate_frame(
+    fin: bool,
+    rsv: u8,
+    is_mask: bool,
+    opcode: u8,
+    payload_length: u64,
+    allowed_rsv: u8,
+) -> WsGonzaleResult<()> {
+    if !is_mask {
+        return Err(WsGonzaleError::UnmaskedFrame);
+    }
+    if rsv & !allowed_rsv != 0 {
+        return Err(WsGonzaleError::ReservedBitsSet);
+    }
+    if Opcode::from(opcode) == Opcode::Unknown {
+        return Err(WsGonzaleError::UnknownOpcode);
+    }
+    if Opcode::is_control(opcode) {
+        if !fin {
+            return Err(WsGonzaleError::FragmentedControlFrame);
+        }
+        if payload_length > 125 {
+            return Err(WsGonzaleError::ControlFrameTooLarge);
+        }
+    }
+    Ok(())
+}
 impl Dataframe {
+    /// Checks the frame against the rules in RFC 6455 section 5 for frames sent by a client
+    pub fn validate(&self) -> WsGonzaleResult<()> {
+        self.validate_with_rsv(0)
+    }
+    /// Same as [`Dataframe::validate`] but allows the RSV bits in `allowed_rsv` that the negotiated extensions claimed.
+    /// Which frames they may be set on is up to the [`Extension`](`crate::extension::Extension`).
+    pub fn validate_with_rsv(&self, allowed_rsv: u8) -> WsGonzaleResult<()> {
+        validate_frame(
+            self.fin,
+            self.get_rsv(),
+            self.is_mask,
+            self.opcode,
+            self.payload_length,
+            allowed_rsv,
+        )
+    }
     #[inline(always)]
     pub fn get_message(self) -> WsGonzaleResult<Message> {
-        let result = match self.opcode {
-            1 => Message::Text(
-                String::from_utf8_lossy(&self.get_payload())
-                    .parse()
-                    .map_err(|_| WsGonzaleError::InvalidPayload)?,
-            ),
-            8 => Message::Close,
-            _ => Message::Unknown,
-        };
-        Ok(result)
+        get_message_from_payload(self.opcode, self.payload)
     }
     #[inline(always)]
     pub fn is_fin(&self) -> bool {
@@ -288,6 +429,20 @@ impl Dataframe {
     pub fn is_rsv3(&self) -> bool {
         self.rsv3
     }
+    /// RSV1, RSV2 and RSV3 at their positions in the first byte of the frame
+    pub fn get_rsv(&self) -> u8 {
+        let mut rsv = 0;
+        if self.rsv1 {
+            rsv |= frame_positions::RSV1;
+        }
+        if self.rsv2 {
+            rsv |= frame_positions::RSV2;
+        }
+        if self.rsv3 {
+            rsv |= frame_positions::RSV3;
+        }
+        rsv
+    }
     #[inline(always)]
     pub fn get_opcode(&self) -> u8 {
         self.opcode
@@ -304,15 +459,187 @@ impl Dataframe {
         self.full_frame_length
     }
     #[inline(always)]
+    pub fn get_masking_key(&self) -> [u8; 4] {
+        self.masking_key
+    }
+    #[inline(always)]
     pub fn get_payload(self) -> Vec<u8> {
         self.payload
     }
 }
+/// A frame parsed from a borrowed buffer without copying it, [`DataframeRef::get_payload`] only allocates to unmask a masked payload.
+/// Use [`DataframeRef::parse_mut`] to unmask it in place instead.
+#[derive(Debug)]
+pub struct DataframeRef<'a> {
+    fin: bool,
+    rsv: u8,
+    is_mask: bool,
+    opcode: u8,
+    payload_length: u64,
+    full_frame_length: u64,
+    masking_key: [u8; 4],
+    payload: &'a [u8],
+    /// Whether `payload` still has to be unmasked
+    is_payload_masked: bool,
+}
+impl<'a> DataframeRef<'a> {
+    /// Parses the frame at the start of `buffer`, `None` means the frame hasn't fully arrived yet.
+    /// Anything after the frame is left alone, [`DataframeRef::get_full_frame_length`] tells where the next frame starts.
+    pub fn parse(buffer: &'a [u8]) -> WsGonzaleResult<Option<DataframeRef<'a>>> {
+        let (header_length, payload_length) = match DataframeRef::get_lengths(buffer)? {
+            Some(lengths) => lengths,
+            None => return Ok(None),
+        };
+        let (first, second) = (buffer[0], buffer[1]);
+        let is_mask = second & frame_positions::IS_MASK == frame_positions::IS_MASK;
+        let mut masking_key = [0u8; 4];
+        if is_mask {
+            masking_key.copy_from_slice(&buffer[header_length - 4..header_length]);
+        }
+        let full_frame_length = header_length + payload_length;
+        Ok(Some(DataframeRef {
+            fin: first & frame_positions::FIN == frame_positions::FIN,
+            rsv: first & (frame_positions::RSV1 | frame_positions::RSV2 | frame_positions::RSV3),
+            is_mask,
+            opcode: first & frame_positions::MASK_OPCODE,
+            payload_length: payload_length as u64,
+            full_frame_length: full_frame_length as u64,
+            masking_key,
+            payload: &buffer[header_length..full_frame_length],
+            is_payload_masked: is_mask,
+        }))
+    }
+    /// Same as [`DataframeRef::parse`] but unmasks the payload in `buffer` so [`DataframeRef::get_payload`] never allocates.
+    /// Parsing the same bytes twice masks the payload again, so every buffer should only be parsed once.
+    pub fn parse_mut(buffer: &'a mut [u8]) -> WsGonzaleResult<Option<DataframeRef<'a>>> {
+        let (header_length, payload_length) = match DataframeRef::get_lengths(buffer)? {
+            Some(lengths) => lengths,
+            None => return Ok(None),
+        };
+        if buffer[1] & frame_positions::IS_MASK == frame_positions::IS_MASK {
+            let mut masking_key = [0u8; 4];
+            masking_key.copy_from_slice(&buffer[header_length - 4..header_length]);
+            let payload = &mut buffer[header_length..header_length + payload_length];
+            mask_payload(&mut &mut *payload, masking_key);
+        }
+        let buffer: &'a [u8] = buffer;
+        Ok(DataframeRef::parse(buffer)?.map(|dataframe| DataframeRef {
+            is_payload_masked: false,
+            ..dataframe
+        }))
+    }
+    /// The length of the header and the payload once the whole frame has arrived
+    fn get_lengths(buffer: &[u8]) -> WsGonzaleResult<Option<(usize, usize)>> {
+        let (header_length, payload_length) = match decoder::get_frame_lengths(buffer) {
+            Some(lengths) => lengths,
+            None => return Ok(None),
+        };
+        let full_frame_length = header_length as u64 + payload_length;
+        if full_frame_length > usize::MAX as u64 {
+            return Err(WsGonzaleError::FrameTooLarge);
+        }
+        if (buffer.len() as u64) < full_frame_length {
+            return Ok(None);
+        }
+        Ok(Some((header_length, payload_length as usize)))
+    }
+    /// Same as [`Dataframe::validate`]
+    pub fn validate(&self) -> WsGonzaleResult<()> {
+        self.validate_with_rsv(0)
+    }
+    /// Same as [`Dataframe::validate_with_rsv`]
+    pub fn validate_with_rsv(&self, allowed_rsv: u8) -> WsGonzaleResult<()> {
+        validate_frame(
+            self.fin,
+            self.rsv,
+            self.is_mask,
+            self.opcode,
+            self.payload_length,
+            allowed_rsv,
+        )
+    }
+    /// Copies the payload into an owned [`Message`]
+    pub fn get_message(&self) -> WsGonzaleResult<Message> {
+        get_message_from_payload(self.opcode, self.get_payload().into_owned())
+    }
+    #[inline(always)]
+    pub fn is_fin(&self) -> bool {
+        self.fin
+    }
+    #[inline(always)]
+    pub fn is_rsv1(&self) -> bool {
+        self.rsv & frame_positions::RSV1 == frame_positions::RSV1
+    }
+    #[inline(always)]
+    pub fn is_rsv2(&self) -> bool {
+        self.rsv & frame_positions::RSV2 == frame_positions::RSV2
+    }
+    #[inline(always)]
+    pub fn is_rsv3(&self) -> bool {
+        self.rsv & frame_positions::RSV3 == frame_positions::RSV3
+    }
+    /// RSV1, RSV2 and RSV3 at their positions in the first byte of the frame
+    #[inline(always)]
+    pub fn get_rsv(&self) -> u8 {
+        self.rsv
+    }
+    #[inline(always)]
+    pub fn get_opcode(&self) -> u8 {
+        self.opcode
+    }
+    #[inline(always)]
+    pub fn is_mask(&self) -> bool {
+        self.is_mask
+    }
+    #[inline(always)]
+    pub fn get_payload_length(&self) -> u64 {
+        self.payload_length
+    }
+    pub fn get_full_frame_length(&self) -> u64 {
+        self.full_frame_length
+    }
+    #[inline(always)]
+    pub fn get_masking_key(&self) -> [u8; 4] {
+        self.masking_key
+    }
+    /// The unmasked payload, it's borrowed from the parsed buffer unless it still had to be unmasked
+    #[inline(always)]
+    pub fn get_payload(&self) -> Cow<'a, [u8]> {
+        if self.is_payload_masked {
+            let mut payload = self.payload.to_vec();
+            mask_payload(&mut &mut *payload, self.masking_key);
+            Cow::Owned(payload)
+        } else {
+            Cow::Borrowed(self.payload)
+        }
+    }
+    /// The payload as it is in the parsed buffer, it's masked if [`DataframeRef::is_mask`] unless it was parsed with [`DataframeRef::parse_mut`]
+    #[inline(always)]
+    pub fn get_raw_payload(&self) -> &'a [u8] {
+        self.payload
+    }
+}
+impl From<DataframeRef<'_>> for Dataframe {
+    fn from(dataframe: DataframeRef<'_>) -> Dataframe {
+        Dataframe {
+            fin: dataframe.fin,
+            rsv1: dataframe.is_rsv1(),
+            rsv2: dataframe.is_rsv2(),
+            rsv3: dataframe.is_rsv3(),
+            is_mask: dataframe.is_mask,
+            opcode: dataframe.opcode,
+            payload_length: dataframe.payload_length,
+            full_frame_length: dataframe.full_frame_length,
+            masking_key: dataframe.masking_key,
+            payload: dataframe.get_payload().into_owned(),
+        }
+    }
+}
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::message::Message;
+    use crate::message::{CloseCode, Message};
     #[test]
     #[should_panic]
     fn test_buffer_with_no_payload_or_masking_key_but_payload_length() {
@@ -339,17 +666,41 @@ mod tests {
             129, // FIN(128) + Opcode(1)
             0,
         ];
-        let result = DataframeBuilder::new(buffer);
-        assert_eq!(result.err().unwrap(), WsGonzaleError::InvalidPayload);
+        let dataframe: Dataframe = DataframeBuilder::new(buffer).unwrap();
+        assert_eq!(
+            dataframe.get_message().unwrap(),
+            Message::Text("".to_string())
+        );
     }
     #[test]
     fn test_close_frame_from_client() {
         let buffer: Vec<u8> = vec![
             136, // FIN(128) + Opcode(8)
             128, // MASK(128)
+            0, 0, 0, 0,
         ];
-        let result = DataframeBuilder::new(buffer);
-        assert_eq!(result.err().unwrap(), WsGonzaleError::ConnectionClosed);
+        let dataframe: Dataframe = DataframeBuilder::new(buffer).unwrap();
+        assert_eq!(dataframe.get_message().unwrap(), Message::Close(None));
+    }
+    #[test]
+    fn test_close_frame_with_reason_from_client() {
+        let buffer: Vec<u8> = vec![
+            136, // FIN(128) + Opcode(8)
+            133, // MASK(128) + PayloadLength(5)
+            0, 0, 0, 0, 3, 232, 66, 121, 101,
+        ];
+        let dataframe: Dataframe = DataframeBuilder::new(buffer).unwrap();
+        assert_eq!(
+            dataframe.get_message().unwrap(),
+            Message::Close(Some(CloseFrame::new(CloseCode::Normal, "Bye")))
+        );
+    }
+    #[test]
+    fn test_get_buffer_close() {
+        let close_frame = CloseFrame::new(CloseCode::Normal, "Bye");
+        let buffer = get_buffer(Message::Close(Some(close_frame)));
+        assert_eq!(buffer, vec![136, 5, 3, 232, 66, 121, 101]);
+        assert!(is_close_buffer(&buffer));
     }
     #[test]
     fn test_buffer_with_no_payload_with_masking_key() {
@@ -386,6 +737,208 @@ mod tests {
         assert_eq!(dataframe.get_payload_length(), s.len() as u64);
     }
 
+    #[test]
+    fn test_buffer_binary() {
+        let buffer: Vec<u8> = vec![
+            130, // FIN(128) + Opcode(2)
+            132, // MASK(128) + PayloadLength(4)
+            1, 2, 3, 4, 1, 2, 3, 4,
+        ];
+        let dataframe: Dataframe = DataframeBuilder::new(buffer).unwrap();
+        let message = dataframe.get_message().unwrap();
+        assert_eq!(message, Message::Binary(vec![0, 0, 0, 0]));
+    }
+    #[test]
+    fn test_get_buffer_binary() {
+        let buffer = get_buffer(Message::Binary(vec![0, 159, 146, 150]));
+        assert_eq!(buffer, vec![130, 4, 0, 159, 146, 150]);
+    }
+    #[test]
+    fn test_get_buffer_text() {
+        let buffer = get_buffer(Message::Text("Hi".to_string()));
+        assert_eq!(buffer, vec![129, 2, 72, 105]);
+    }
+    #[test]
+    fn test_buffer_ping() {
+        let buffer: Vec<u8> = vec![
+            137, // FIN(128) + Opcode(9)
+            130, // MASK(128) + PayloadLength(2)
+            0, 0, 0, 0, 72, 105,
+        ];
+        let dataframe: Dataframe = DataframeBuilder::new(buffer).unwrap();
+        let message = dataframe.get_message().unwrap();
+        assert_eq!(message, Message::Ping(b"Hi".to_vec()));
+    }
+    #[test]
+    fn test_get_buffer_pong() {
+        let buffer = get_buffer(Message::Pong(b"Hi".to_vec()));
+        assert_eq!(buffer, vec![138, 2, 72, 105]);
+    }
+    fn round_trip(message: Message) {
+        let encoders = [
+            DataframeEncoder::new(),
+            DataframeEncoder::with_masking_key([90, 212, 118, 181]),
+        ];
+        for encoder in encoders.iter() {
+            let buffer = encoder.encode(&message);
+            let dataframe: Dataframe = DataframeBuilder::new(buffer).unwrap();
+            assert!(dataframe.is_fin());
+            assert_eq!(dataframe.is_mask(), encoder.masking_key.is_some());
+            assert_eq!(dataframe.get_message().unwrap(), message);
+        }
+    }
+    #[test]
+    fn test_encoder_round_trip() {
+        for size in [0, 1, 125, 126, 127, 0xFFFF, 0x10000, 100000].iter() {
+            let s = (0..*size)
+                .map(|i| (b'a' + (i % 26) as u8) as char)
+                .collect::<String>();
+            round_trip(Message::Text(s));
+            round_trip(Message::Binary((0..*size).map(|i| i as u8).collect()));
+        }
+        round_trip(Message::Ping(b"Hello".to_vec()));
+        round_trip(Message::Pong(Vec::new()));
+        round_trip(Message::Close(None));
+        round_trip(Message::Close(Some(CloseFrame::new(
+            CloseCode::Application(4000),
+            "Bye",
+        ))));
+    }
+    #[test]
+    fn test_encoder_length_header() {
+        let encoder = DataframeEncoder::new();
+        let buffer = encoder.encode(&Message::Binary(vec![0; 125]));
+        assert_eq!(buffer[..2], [130, 125]);
+        let buffer = encoder.encode(&Message::Binary(vec![0; 126]));
+        assert_eq!(buffer[..4], [130, 126, 0, 126]);
+        let buffer = encoder.encode(&Message::Binary(vec![0; 0x10000]));
+        assert_eq!(buffer[..10], [130, 127, 0, 0, 0, 0, 0, 1, 0, 0]);
+        assert_eq!(buffer.len(), 10 + 0x10000);
+    }
+    #[test]
+    fn test_encoder_masked_hello_world() {
+        let encoder = DataframeEncoder::with_masking_key([90, 212, 118, 181]);
+        let buffer = encoder.encode(&Message::Text("Hello World".to_string()));
+        assert_eq!(
+            buffer,
+            vec![129, 139, 90, 212, 118, 181, 18, 177, 26, 217, 53, 244, 33, 218, 40, 184, 18]
+        );
+    }
+    #[test]
+    fn test_encoder_fragment() {
+        let buffer = DataframeEncoder::new().encode_frame(false, Opcode::Text as u8, b"Hi");
+        assert_eq!(buffer, vec![1, 2, 72, 105]);
+    }
+    fn validate(buffer: Vec<u8>) -> WsGonzaleResult<()> {
+        DataframeBuilder::new(buffer).unwrap().validate()
+    }
+    #[test]
+    fn test_validate() {
+        // FIN(128) + Opcode(1), MASK(128)
+        assert_eq!(validate(vec![129, 128, 0, 0, 0, 0]), Ok(()));
+        // FIN(128) + Opcode(1) without MASK
+        assert_eq!(validate(vec![129, 0]), Err(WsGonzaleError::UnmaskedFrame));
+        // FIN(128) + RSV1(64) + Opcode(1)
+        assert_eq!(
+            validate(vec![193, 128, 0, 0, 0, 0]),
+            Err(WsGonzaleError::ReservedBitsSet)
+        );
+        // FIN(128) + Opcode(3)
+        assert_eq!(
+            validate(vec![131, 128, 0, 0, 0, 0]),
+            Err(WsGonzaleError::UnknownOpcode)
+        );
+        // Opcode(9) without FIN
+        assert_eq!(
+            validate(vec![9, 128, 0, 0, 0, 0]),
+            Err(WsGonzaleError::FragmentedControlFrame)
+        );
+        // FIN(128) + Opcode(9), MASK(128) + PayloadLength(126)
+        let mut buffer = vec![137, 254, 0, 126, 0, 0, 0, 0];
+        buffer.extend_from_slice(&[0; 126]);
+        assert_eq!(validate(buffer), Err(WsGonzaleError::ControlFrameTooLarge));
+    }
+    #[test]
+    fn test_validate_with_rsv() {
+        let validate = |buffer: Vec<u8>| {
+            DataframeBuilder::new(buffer)
+                .unwrap()
+                .validate_with_rsv(frame_positions::RSV1)
+        };
+        // FIN(128) + RSV1(64) + Opcode(1)
+        assert_eq!(validate(vec![193, 128, 0, 0, 0, 0]), Ok(()));
+        // FIN(128) + RSV2(32) + Opcode(1)
+        assert_eq!(
+            validate(vec![161, 128, 0, 0, 0, 0]),
+            Err(WsGonzaleError::ReservedBitsSet)
+        );
+        // FIN(128) + RSV1(64) + RSV3(16) + Opcode(1)
+        assert_eq!(
+            validate(vec![209, 128, 0, 0, 0, 0]),
+            Err(WsGonzaleError::ReservedBitsSet)
+        );
+    }
+    #[test]
+    fn test_mask_payload_matches_byte_by_byte() {
+        let mask = [90, 212, 118, 181];
+        for length in 0..100 {
+            let payload: Vec<u8> = (0..length).map(|i| (i * 7) as u8).collect();
+            let expected: Vec<u8> = payload
+                .iter()
+                .enumerate()
+                .map(|(i, byte)| byte ^ mask[i % 4])
+                .collect();
+            let mut masked = payload.clone();
+            assert_eq!(mask_payload(&mut &mut *masked, mask), expected.as_slice());
+            // Masking twice gives back the payload
+            mask_payload(&mut &mut *masked, mask);
+            assert_eq!(masked, payload);
+        }
+    }
+    #[test]
+    fn test_dataframe_ref() {
+        let mut buffer: Vec<u8> = vec![
+            129, 139, 90, 212, 118, 181, 18, 177, 26, 217, 53, 244, 33, 218, 40, 184, 18,
+        ];
+        // Start of the next frame
+        buffer.extend_from_slice(&[129, 139]);
+        let dataframe = DataframeRef::parse(&buffer).unwrap().unwrap();
+        assert_eq!(&*dataframe.get_payload(), b"Hello World");
+        assert_eq!(dataframe.get_raw_payload(), &buffer[6..17]);
+        assert_eq!(dataframe.get_full_frame_length(), 17);
+        assert_eq!(dataframe.validate(), Ok(()));
+        assert_eq!(
+            dataframe.get_message().unwrap(),
+            Message::Text("Hello World".to_string())
+        );
+        assert!(DataframeRef::parse(&buffer[17..]).unwrap().is_none());
+        // The payload is unmasked in place and borrowed
+        let dataframe = DataframeRef::parse_mut(&mut buffer).unwrap().unwrap();
+        assert!(matches!(
+            dataframe.get_payload(),
+            Cow::Borrowed(b"Hello World")
+        ));
+        assert_eq!(&buffer[6..17], b"Hello World");
+    }
+    #[test]
+    fn test_dataframe_ref_matches_dataframe() {
+        let masked = DataframeEncoder::with_masking_key([1, 2, 3, 4]);
+        for payload in [vec![], vec![7; 125], vec![7; 126], vec![7; 0x10000]].iter() {
+            let buffer = masked.encode_frame(false, Opcode::Binary as u8, payload);
+            let dataframe = DataframeBuilder::new(buffer.clone()).unwrap();
+            let dataframe_ref = DataframeRef::parse(&buffer).unwrap().unwrap();
+            assert_eq!(dataframe_ref.is_fin(), dataframe.is_fin());
+            assert_eq!(dataframe_ref.get_masking_key(), dataframe.get_masking_key());
+            assert_eq!(
+                dataframe_ref.get_full_frame_length(),
+                dataframe.get_full_frame_length()
+            );
+            assert_eq!(
+                dataframe_ref.get_payload().as_ref(),
+                dataframe.get_payload().as_slice()
+            );
+        }
+    }
     #[test]
     fn test_buffer_to_dataframe() {
         let buffer: Vec<u8> = vec![
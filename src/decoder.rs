@@ -0,0 +1,160 @@
+use crate::{
+    config::DEFAULT_MAX_FRAME_SIZE,
+    dataframe::{frame_positions, Dataframe, DataframeRef},
+    WsGonzaleError, WsGonzaleResult,
+};
+
+/// Turns a stream of bytes into [`Dataframe`]s without doing any IO itself.
+/// Feed it whatever was read with [`FrameDecoder::extend`] and take the complete frames with [`FrameDecoder::next_frame`],
+/// the bytes of frames that haven't fully arrived yet are kept in the internal buffer.
+#[derive(Clone, Debug)]
+pub struct FrameDecoder {
+    buffer: Vec<u8>,
+    /// Where the next frame starts in `buffer`, the frames before it were already returned
+    position: usize,
+    max_frame_size: usize,
+}
+impl Default for FrameDecoder {
+    fn default() -> Self {
+        FrameDecoder {
+            buffer: Vec::new(),
+            position: 0,
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+        }
+    }
+}
+impl FrameDecoder {
+    pub fn new() -> FrameDecoder {
+        FrameDecoder::default()
+    }
+    /// Frames with a larger payload are rejected as soon as their header has arrived
+    pub fn with_max_frame_size(mut self, max_frame_size: usize) -> FrameDecoder {
+        self.max_frame_size = max_frame_size;
+        self
+    }
+    /// Appends a chunk of bytes, it can be any size and doesn't have to line up with the frames
+    pub fn extend(&mut self, bytes: &[u8]) {
+        // The returned frames are only dropped here so every read moves the rest of the buffer at most once
+        if self.position > 0 {
+            self.buffer.drain(..self.position);
+            self.position = 0;
+        }
+        self.buffer.extend_from_slice(bytes);
+    }
+    /// Bytes that are buffered but not yet returned as a [`Dataframe`]
+    pub fn get_buffered_length(&self) -> usize {
+        self.buffer.len() - self.position
+    }
+    /// Returns the next [`Dataframe`] once all of its bytes have arrived, `None` means we need more bytes.
+    /// It's parsed in place with a [`DataframeRef`], the unmasked payload is the only thing that's copied.
+    pub fn next_frame(&mut self) -> WsGonzaleResult<Option<Dataframe>> {
+        let buffer = &self.buffer[self.position..];
+        match get_frame_lengths(buffer) {
+            Some((_, payload_length)) if payload_length > self.max_frame_size as u64 => {
+                return Err(WsGonzaleError::FrameTooLarge)
+            }
+            Some(_) => {}
+            None => return Ok(None),
+        }
+        let dataframe = match DataframeRef::parse(buffer)? {
+            Some(dataframe) => dataframe,
+            None => return Ok(None),
+        };
+        let frame_length = dataframe.get_full_frame_length() as usize;
+        let dataframe = Dataframe::from(dataframe);
+        self.position += frame_length;
+        if self.position == self.buffer.len() {
+            self.buffer.clear();
+            self.position = 0;
+        }
+        Ok(Some(dataframe))
+    }
+}
+/// The length of the header and the payload of a frame, `None` if the header hasn't fully arrived yet
+pub(crate) fn get_frame_lengths(buffer: &[u8]) -> Option<(usize, u64)> {
+    let second = *buffer.get(1)?;
+    let masking_key_length = if second & frame_positions::IS_MASK == frame_positions::IS_MASK {
+        4
+    } else {
+        0
+    };
+    let (extra_length_bytes, payload_length) = match second & frame_positions::MASK_PAYLOAD_LENGTH {
+        126 => {
+            let bytes = buffer.get(2..4)?;
+            (2, u16::from_be_bytes([bytes[0], bytes[1]]) as u64)
+        }
+        127 => {
+            let mut bytes = [0u8; 8];
+            bytes.copy_from_slice(buffer.get(2..10)?);
+            (8, u64::from_be_bytes(bytes))
+        }
+        size => (0, size as u64),
+    };
+    let header_length = 2 + extra_length_bytes + masking_key_length;
+    Some((header_length, payload_length))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::Message;
+
+    const HELLO_WORLD: [u8; 17] = [
+        129, 139, 90, 212, 118, 181, 18, 177, 26, 217, 53, 244, 33, 218, 40, 184, 18,
+    ];
+    #[test]
+    fn test_byte_by_byte() {
+        let mut decoder = FrameDecoder::new();
+        for byte in HELLO_WORLD[..16].iter() {
+            decoder.extend(&[*byte]);
+            assert!(decoder.next_frame().unwrap().is_none());
+        }
+        decoder.extend(&HELLO_WORLD[16..]);
+        let dataframe = decoder.next_frame().unwrap().unwrap();
+        assert_eq!(
+            dataframe.get_message().unwrap(),
+            Message::Text("Hello World".to_string())
+        );
+        assert_eq!(decoder.get_buffered_length(), 0);
+    }
+    #[test]
+    fn test_multiple_frames_in_one_chunk() {
+        let mut decoder = FrameDecoder::new();
+        let mut chunk = HELLO_WORLD.to_vec();
+        chunk.extend_from_slice(&HELLO_WORLD);
+        // Start of a third frame
+        chunk.extend_from_slice(&HELLO_WORLD[..3]);
+        decoder.extend(&chunk);
+        assert!(decoder.next_frame().unwrap().is_some());
+        assert!(decoder.next_frame().unwrap().is_some());
+        assert!(decoder.next_frame().unwrap().is_none());
+        assert_eq!(decoder.get_buffered_length(), 3);
+        // The rest of the third frame
+        decoder.extend(&HELLO_WORLD[3..]);
+        let dataframe = decoder.next_frame().unwrap().unwrap();
+        assert_eq!(dataframe.get_full_frame_length(), 17);
+        assert_eq!(decoder.get_buffered_length(), 0);
+    }
+    #[test]
+    fn test_frame_too_large() {
+        let mut decoder = FrameDecoder::new().with_max_frame_size(125);
+        // FIN(128) + Opcode(2), PayloadLength(126) and a u16 length of 126
+        decoder.extend(&[130, 126, 0, 126]);
+        assert_eq!(
+            decoder.next_frame().err().unwrap(),
+            WsGonzaleError::FrameTooLarge
+        );
+    }
+    #[test]
+    fn test_split_extended_length() {
+        let mut decoder = FrameDecoder::new();
+        // FIN(128) + Opcode(2), PayloadLength(127) and the first bytes of the u64 length
+        decoder.extend(&[130, 127, 0, 0, 0]);
+        assert!(decoder.next_frame().unwrap().is_none());
+        decoder.extend(&[0, 0, 0, 1, 0]);
+        assert!(decoder.next_frame().unwrap().is_none());
+        decoder.extend(&vec![7; 256]);
+        let dataframe = decoder.next_frame().unwrap().unwrap();
+        assert_eq!(dataframe.get_payload(), vec![7; 256]);
+    }
+}
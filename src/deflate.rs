@@ -0,0 +1,485 @@
+use {
+    crate::{
+        config::DEFAULT_MAX_MESSAGE_SIZE,
+        dataframe::{frame_positions, Opcode},
+        extension::{Extension, Frame},
+        handshake::ExtensionOffer,
+        WsGonzaleError, WsGonzaleResult,
+    },
+    flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress, Status},
+};
+
+/// Name of the extension in the `Sec-WebSocket-Extensions` header
+pub const PERMESSAGE_DEFLATE: &str = "permessage-deflate";
+/// Every compressed message ends with this, it's removed before sending and added back before inflating
+const DEFLATE_TAIL: [u8; 4] = [0, 0, 255, 255];
+/// How much more room we make in the output at a time while (de)compressing
+const CHUNK_SIZE: usize = 8192;
+
+/// Settings for the permessage-deflate extension, see RFC 7692
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DeflateConfig {
+    /// 0-9, 0 is no compression and 9 is the best compression
+    pub compression_level: u32,
+    /// Start every message we send with an empty window instead of the one from the last message.
+    /// Saves memory between messages at the cost of compression.
+    pub server_no_context_takeover: bool,
+    /// Ask the client to do the same for the messages it sends
+    pub client_no_context_takeover: bool,
+    /// Size of the LZ77 window (9-15) we compress with, a smaller window uses less memory
+    pub server_max_window_bits: u8,
+    /// Size of the LZ77 window (8-15) we ask the client to compress with, only possible if the client offered `client_max_window_bits`
+    pub client_max_window_bits: u8,
+}
+impl Default for DeflateConfig {
+    fn default() -> Self {
+        DeflateConfig {
+            compression_level: Compression::default().level(),
+            server_no_context_takeover: false,
+            client_no_context_takeover: false,
+            server_max_window_bits: 15,
+            client_max_window_bits: 15,
+        }
+    }
+}
+/// What we agreed on with the client during the handshake
+#[derive(Clone, Debug, PartialEq)]
+pub struct DeflateParams {
+    compression_level: u32,
+    server_no_context_takeover: bool,
+    client_no_context_takeover: bool,
+    server_max_window_bits: u8,
+    client_max_window_bits: Option<u8>,
+    /// Whether the client asked for a window size so we have to answer with one
+    has_server_max_window_bits: bool,
+}
+impl DeflateParams {
+    /// Accepts the first permessage-deflate offer that we can agree on with `config`
+    pub fn negotiate(config: &DeflateConfig, offers: &[ExtensionOffer]) -> Option<DeflateParams> {
+        offers
+            .iter()
+            .filter(|offer| offer.name.eq_ignore_ascii_case(PERMESSAGE_DEFLATE))
+            .find_map(|offer| DeflateParams::from_offer(config, offer))
+    }
+    /// Unknown, repeated or invalid parameters decline the offer
+    fn from_offer(config: &DeflateConfig, offer: &ExtensionOffer) -> Option<DeflateParams> {
+        let mut params = DeflateParams {
+            compression_level: config.compression_level,
+            server_no_context_takeover: config.server_no_context_takeover,
+            client_no_context_takeover: config.client_no_context_takeover,
+            server_max_window_bits: config.server_max_window_bits.clamp(9, 15),
+            client_max_window_bits: None,
+            has_server_max_window_bits: false,
+        };
+        let mut seen: Vec<&str> = Vec::new();
+        for param in &offer.params {
+            let name = param.name.as_str();
+            if seen.contains(&name) {
+                return None;
+            }
+            seen.push(name);
+            match (name, param.value.as_deref()) {
+                ("server_no_context_takeover", None) => params.server_no_context_takeover = true,
+                ("client_no_context_takeover", None) => params.client_no_context_takeover = true,
+                ("server_max_window_bits", Some(value)) => {
+                    // We can't compress with a window of 8 bits, so that has to be declined
+                    let bits = parse_window_bits(value).filter(|bits| *bits > 8)?;
+                    params.server_max_window_bits = params.server_max_window_bits.min(bits);
+                    params.has_server_max_window_bits = true;
+                }
+                ("client_max_window_bits", None) => {
+                    params.client_max_window_bits = Some(config.client_max_window_bits);
+                }
+                ("client_max_window_bits", Some(value)) => {
+                    let bits = parse_window_bits(value)?;
+                    params.client_max_window_bits = Some(config.client_max_window_bits.min(bits));
+                }
+                _ => return None,
+            }
+        }
+        // We can only ask for a smaller client window if the client said it supports it
+        if params.client_max_window_bits.is_none() && config.client_max_window_bits < 15 {
+            return None;
+        }
+        Some(params)
+    }
+    /// What goes in the `Sec-WebSocket-Extensions` header of our handshake response
+    pub fn get_response(&self) -> ExtensionOffer {
+        let mut response = ExtensionOffer::new(PERMESSAGE_DEFLATE);
+        if self.server_no_context_takeover {
+            response = response.with_param("server_no_context_takeover", None);
+        }
+        if self.client_no_context_takeover {
+            response = response.with_param("client_no_context_takeover", None);
+        }
+        if self.has_server_max_window_bits || self.server_max_window_bits < 15 {
+            let bits = self.server_max_window_bits.to_string();
+            response = response.with_param("server_max_window_bits", Some(&bits));
+        }
+        if let Some(client_max_window_bits) = self.client_max_window_bits {
+            let bits = client_max_window_bits.to_string();
+            response = response.with_param("client_max_window_bits", Some(&bits));
+        }
+        response
+    }
+    pub fn get_deflater(&self) -> Deflater {
+        Deflater {
+            compress: Compress::new_with_window_bits(
+                Compression::new(self.compression_level),
+                false,
+                self.server_max_window_bits,
+            ),
+            no_context_takeover: self.server_no_context_takeover,
+        }
+    }
+    pub fn get_inflater(&self) -> Inflater {
+        Inflater {
+            // A window of 15 bits can inflate anything compressed with a smaller window
+            decompress: Decompress::new_with_window_bits(false, 15),
+            no_context_takeover: self.client_no_context_takeover,
+        }
+    }
+}
+fn parse_window_bits(value: &str) -> Option<u8> {
+    if value.is_empty() || !value.bytes().all(|byte| byte.is_ascii_digit()) {
+        return None;
+    }
+    value.parse().ok().filter(|bits| (8..=15).contains(bits))
+}
+/// Compresses the messages we send
+pub struct Deflater {
+    compress: Compress,
+    no_context_takeover: bool,
+}
+impl Deflater {
+    /// Compresses the payload of a whole message, the deflate tail is removed
+    pub fn compress(&mut self, payload: &[u8]) -> WsGonzaleResult<Vec<u8>> {
+        let mut output: Vec<u8> = Vec::with_capacity(payload.len() / 2 + CHUNK_SIZE);
+        let mut input = payload;
+        loop {
+            let total_in = self.compress.total_in();
+            self.compress
+                .compress_vec(input, &mut output, FlushCompress::Sync)
+                .map_err(|_| WsGonzaleError::InvalidPayload)?;
+            input = &input[(self.compress.total_in() - total_in) as usize..];
+            // Everything is flushed once the input is consumed and there was room left in the output
+            if input.is_empty() && output.len() < output.capacity() {
+                break;
+            }
+            output.reserve(CHUNK_SIZE);
+        }
+        if output.ends_with(&DEFLATE_TAIL) {
+            output.truncate(output.len() - DEFLATE_TAIL.len());
+        }
+        if self.no_context_takeover {
+            self.compress.reset();
+        }
+        Ok(output)
+    }
+}
+/// Inflates the compressed messages we receive
+pub struct Inflater {
+    decompress: Decompress,
+    no_context_takeover: bool,
+}
+impl Inflater {
+    /// Inflates one fragment of a compressed message into `output`, the deflate tail is added after the last fragment.
+    /// Fails as soon as `output` grows over `max_size` so a small message can't inflate into gigabytes.
+    pub fn decompress(
+        &mut self,
+        input: &[u8],
+        is_fin: bool,
+        output: &mut Vec<u8>,
+        max_size: usize,
+    ) -> WsGonzaleResult<()> {
+        self.decompress_chunk(input, output, max_size)?;
+        if is_fin {
+            self.decompress_chunk(&DEFLATE_TAIL, output, max_size)?;
+            if self.no_context_takeover {
+                self.decompress.reset(false);
+            }
+        }
+        Ok(())
+    }
+    fn decompress_chunk(
+        &mut self,
+        mut input: &[u8],
+        output: &mut Vec<u8>,
+        max_size: usize,
+    ) -> WsGonzaleResult<()> {
+        loop {
+            output.reserve(CHUNK_SIZE.min(max_size.saturating_sub(output.len()) + 1));
+            let total_in = self.decompress.total_in();
+            let status = self
+                .decompress
+                .decompress_vec(input, output, FlushDecompress::Sync)
+                .map_err(|_| WsGonzaleError::InvalidCompression)?;
+            input = &input[(self.decompress.total_in() - total_in) as usize..];
+            if output.len() > max_size {
+                return Err(WsGonzaleError::MessageTooLarge);
+            }
+            // Everything is inflated once the input is consumed and there was room left in the output
+            if (input.is_empty() && output.len() < output.capacity()) || status == Status::StreamEnd
+            {
+                return Ok(());
+            }
+        }
+    }
+}
+/// The permessage-deflate [`Extension`], compresses whole text and binary messages we send
+/// and inflates the messages the client sent with RSV1 set.
+pub struct PerMessageDeflate {
+    config: DeflateConfig,
+    max_message_size: usize,
+    deflater: Option<Deflater>,
+    inflater: Option<Inflater>,
+    /// Whether the message we are receiving is compressed, the continuation frames don't have RSV1 set
+    is_inflating: bool,
+    /// How much the message we are receiving has inflated to so far
+    inflated_length: usize,
+}
+impl PerMessageDeflate {
+    pub fn new(config: DeflateConfig) -> PerMessageDeflate {
+        PerMessageDeflate {
+            config,
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+            deflater: None,
+            inflater: None,
+            is_inflating: false,
+            inflated_length: 0,
+        }
+    }
+    /// Messages that inflate to more than this fail with [`WsGonzaleError::MessageTooLarge`], defaults to [`DEFAULT_MAX_MESSAGE_SIZE`]
+    pub fn with_max_message_size(mut self, max_message_size: usize) -> PerMessageDeflate {
+        self.max_message_size = max_message_size;
+        self
+    }
+}
+impl Extension for PerMessageDeflate {
+    fn get_name(&self) -> &str {
+        PERMESSAGE_DEFLATE
+    }
+    fn get_rsv(&self) -> u8 {
+        frame_positions::RSV1
+    }
+    fn negotiate(&mut self, offers: &[ExtensionOffer]) -> Option<ExtensionOffer> {
+        let params = DeflateParams::negotiate(&self.config, offers)?;
+        self.deflater = Some(params.get_deflater());
+        self.inflater = Some(params.get_inflater());
+        Some(params.get_response())
+    }
+    fn decode(&mut self, mut frame: Frame) -> WsGonzaleResult<Frame> {
+        let inflater = match self.inflater.as_mut() {
+            Some(inflater) => inflater,
+            None => return Ok(frame),
+        };
+        let is_compressed = frame.rsv & frame_positions::RSV1 != 0;
+        let is_control = Opcode::is_control(frame.opcode);
+        let is_continuation = frame.opcode == Opcode::Continuation as u8;
+        // RSV1 is only allowed on the first frame of a message
+        if is_compressed && (is_control || is_continuation) {
+            return Err(WsGonzaleError::ReservedBitsSet);
+        }
+        if is_control {
+            return Ok(frame);
+        }
+        if !is_continuation {
+            self.is_inflating = is_compressed;
+            self.inflated_length = 0;
+        }
+        if !self.is_inflating {
+            return Ok(frame);
+        }
+        let mut payload = Vec::new();
+        let max_size = self.max_message_size.saturating_sub(self.inflated_length);
+        inflater.decompress(&frame.payload, frame.fin, &mut payload, max_size)?;
+        self.inflated_length += payload.len();
+        frame.payload = payload;
+        frame.rsv &= !frame_positions::RSV1;
+        Ok(frame)
+    }
+    /// Only whole messages are compressed, control frames and fragments are sent as they are
+    fn encode(&mut self, mut frame: Frame) -> WsGonzaleResult<Frame> {
+        let deflater = match self.deflater.as_mut() {
+            Some(deflater) => deflater,
+            None => return Ok(frame),
+        };
+        let is_message = frame.fin
+            && frame.rsv == 0
+            && matches!(Opcode::from(frame.opcode), Opcode::Text | Opcode::Binary);
+        if is_message {
+            frame.payload = deflater.compress(&frame.payload)?;
+            frame.rsv |= frame_positions::RSV1;
+        }
+        Ok(frame)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn offers(header: &str) -> Vec<ExtensionOffer> {
+        ExtensionOffer::parse_header(header).unwrap()
+    }
+    fn negotiate(header: &str) -> Option<String> {
+        DeflateParams::negotiate(&DeflateConfig::default(), &offers(header))
+            .map(|params| params.get_response().to_string())
+    }
+    #[test]
+    fn test_negotiate() {
+        assert_eq!(
+            negotiate("permessage-deflate; client_max_window_bits"),
+            Some("permessage-deflate; client_max_window_bits=15".to_string())
+        );
+        assert_eq!(
+            negotiate(
+                "permessage-deflate; server_max_window_bits=\"10\"; server_no_context_takeover"
+            ),
+            Some(
+                "permessage-deflate; server_no_context_takeover; server_max_window_bits=10"
+                    .to_string()
+            )
+        );
+        assert_eq!(negotiate("x-webkit-deflate-frame"), None);
+    }
+    #[test]
+    fn test_negotiate_declines_invalid_offers() {
+        assert_eq!(negotiate("permessage-deflate; unknown_param"), None);
+        assert_eq!(
+            negotiate("permessage-deflate; server_max_window_bits=16"),
+            None
+        );
+        assert_eq!(
+            negotiate("permessage-deflate; server_max_window_bits=8"),
+            None
+        );
+        assert_eq!(
+            negotiate("permessage-deflate; server_no_context_takeover; server_no_context_takeover"),
+            None
+        );
+        // Falls back on the next offer
+        assert_eq!(
+            negotiate("permessage-deflate; server_max_window_bits, permessage-deflate"),
+            Some("permessage-deflate".to_string())
+        );
+    }
+    #[test]
+    fn test_negotiate_client_window_requires_offer() {
+        let config = DeflateConfig {
+            client_max_window_bits: 10,
+            ..DeflateConfig::default()
+        };
+        assert_eq!(
+            DeflateParams::negotiate(&config, &offers("permessage-deflate")),
+            None
+        );
+        let params = DeflateParams::negotiate(
+            &config,
+            &offers("permessage-deflate; client_max_window_bits"),
+        );
+        assert_eq!(
+            params.unwrap().get_response().to_string(),
+            "permessage-deflate; client_max_window_bits=10"
+        );
+    }
+    /// "Hello" compressed, see RFC 7692 section 7.2.3.1
+    const COMPRESSED_HELLO: [u8; 7] = [0xf2, 0x48, 0xcd, 0xc9, 0xc9, 0x07, 0x00];
+    #[test]
+    fn test_rfc_7692_hello() {
+        // The compressed "Hello" from RFC 7692 section 7.2.3.1
+        let params =
+            DeflateParams::negotiate(&DeflateConfig::default(), &offers(PERMESSAGE_DEFLATE))
+                .unwrap();
+        let mut output = Vec::new();
+        let mut inflater = params.get_inflater();
+        inflater
+            .decompress(&COMPRESSED_HELLO, true, &mut output, 100)
+            .unwrap();
+        assert_eq!(output, b"Hello");
+    }
+    #[test]
+    fn test_round_trip_with_context_takeover() {
+        let params =
+            DeflateParams::negotiate(&DeflateConfig::default(), &offers(PERMESSAGE_DEFLATE))
+                .unwrap();
+        let mut deflater = params.get_deflater();
+        let mut inflater = params.get_inflater();
+        let payload = "Hello Hello Hello Hello Hello Hello".as_bytes();
+        let first = deflater.compress(payload).unwrap();
+        let second = deflater.compress(payload).unwrap();
+        // The second message refers back to the first one
+        assert!(second.len() < first.len());
+        for compressed in [first, second].iter() {
+            let mut output = Vec::new();
+            inflater
+                .decompress(compressed, true, &mut output, 100)
+                .unwrap();
+            assert_eq!(output, payload);
+        }
+    }
+    #[test]
+    fn test_decompress_over_max_size() {
+        let params =
+            DeflateParams::negotiate(&DeflateConfig::default(), &offers(PERMESSAGE_DEFLATE))
+                .unwrap();
+        let compressed = params.get_deflater().compress(&vec![0; 100000]).unwrap();
+        let mut output = Vec::new();
+        let result = params
+            .get_inflater()
+            .decompress(&compressed, true, &mut output, 1000);
+        assert_eq!(result, Err(WsGonzaleError::MessageTooLarge));
+    }
+    /// A negotiated permessage-deflate with the default config
+    fn per_message_deflate() -> PerMessageDeflate {
+        let mut deflate = PerMessageDeflate::new(DeflateConfig::default());
+        assert!(deflate.negotiate(&offers(PERMESSAGE_DEFLATE)).is_some());
+        deflate
+    }
+    fn frame(fin: bool, rsv: u8, opcode: Opcode, payload: &[u8]) -> Frame {
+        Frame {
+            fin,
+            rsv,
+            opcode: opcode as u8,
+            payload: payload.to_vec(),
+        }
+    }
+    #[test]
+    fn test_encode() {
+        let mut deflate = per_message_deflate();
+        let compressed = deflate
+            .encode(frame(true, 0, Opcode::Text, b"Hello"))
+            .unwrap();
+        assert_eq!(compressed.get_buffer(), {
+            let mut buffer = vec![193, 7];
+            buffer.extend_from_slice(&COMPRESSED_HELLO);
+            buffer
+        });
+        // Control frames and fragments are left alone
+        let ping = frame(true, 0, Opcode::Ping, b"Hello");
+        assert_eq!(deflate.encode(ping.clone()).unwrap(), ping);
+        let fragment = frame(false, 0, Opcode::Text, b"Hello");
+        assert_eq!(deflate.encode(fragment.clone()).unwrap(), fragment);
+    }
+    #[test]
+    fn test_decode_fragments() {
+        let mut deflate = per_message_deflate();
+        let rsv1 = frame_positions::RSV1;
+        let first = deflate
+            .decode(frame(false, rsv1, Opcode::Text, &COMPRESSED_HELLO[..3]))
+            .unwrap();
+        let last = deflate
+            .decode(frame(true, 0, Opcode::Continuation, &COMPRESSED_HELLO[3..]))
+            .unwrap();
+        assert_eq!(first.rsv, 0);
+        assert_eq!([first.payload, last.payload].concat(), b"Hello");
+        // Uncompressed messages pass through
+        let uncompressed = frame(true, 0, Opcode::Text, b"Hello");
+        assert_eq!(deflate.decode(uncompressed.clone()).unwrap(), uncompressed);
+        // RSV1 on a continuation frame
+        assert_eq!(
+            deflate.decode(frame(true, rsv1, Opcode::Continuation, &[])),
+            Err(WsGonzaleError::ReservedBitsSet)
+        );
+    }
+}
@@ -0,0 +1,221 @@
+use crate::{
+    dataframe::{frame_positions, Dataframe, DataframeEncoder},
+    decoder,
+    handshake::ExtensionOffer,
+    WsGonzaleResult,
+};
+
+/// A frame as an [`Extension`] sees it, the length and masking are taken care of by the codec
+#[derive(Clone, Debug, PartialEq)]
+pub struct Frame {
+    pub fin: bool,
+    /// RSV1, RSV2 and RSV3 at their positions in the first byte, see [`frame_positions`]
+    pub rsv: u8,
+    pub opcode: u8,
+    pub payload: Vec<u8>,
+}
+impl From<Dataframe> for Frame {
+    fn from(dataframe: Dataframe) -> Frame {
+        Frame {
+            fin: dataframe.is_fin(),
+            rsv: dataframe.get_rsv(),
+            opcode: dataframe.get_opcode(),
+            payload: dataframe.get_payload(),
+        }
+    }
+}
+impl Frame {
+    /// Parses a whole unmasked frame from [`get_buffer`](`crate::dataframe::get_buffer`), anything else is returned as the error
+    pub fn from_buffer(mut buffer: Vec<u8>) -> Result<Frame, Vec<u8>> {
+        let (first, second) = match buffer.as_slice() {
+            [first, second, ..] => (*first, *second),
+            _ => return Err(buffer),
+        };
+        let header_length = match decoder::get_frame_lengths(&buffer) {
+            Some((header_length, payload_length))
+                if second & frame_positions::IS_MASK == 0
+                    && header_length as u64 + payload_length == buffer.len() as u64 =>
+            {
+                header_length
+            }
+            _ => return Err(buffer),
+        };
+        Ok(Frame {
+            fin: first & frame_positions::FIN == frame_positions::FIN,
+            rsv: first & (frame_positions::RSV1 | frame_positions::RSV2 | frame_positions::RSV3),
+            opcode: first & frame_positions::MASK_OPCODE,
+            payload: buffer.split_off(header_length),
+        })
+    }
+    /// Encodes the frame the way a server sends it
+    pub fn get_buffer(&self) -> Vec<u8> {
+        DataframeEncoder::new().encode_frame_with_rsv(
+            self.fin,
+            self.rsv,
+            self.opcode,
+            &self.payload,
+        )
+    }
+}
+/// An extension negotiated in the handshake with `Sec-WebSocket-Extensions`, see RFC 6455 section 9.
+/// It sees every frame that's read or written and can claim RSV bits to mark the frames it changed.
+pub trait Extension: Send {
+    /// The name of the extension in `Sec-WebSocket-Extensions`
+    fn get_name(&self) -> &str;
+    /// The RSV bits the extension uses, frames with RSV bits no negotiated extension claimed are a protocol error
+    fn get_rsv(&self) -> u8;
+    /// Gets every offer in the client's `Sec-WebSocket-Extensions` and returns what goes in our response if it
+    /// accepts one of them, e.g. `permessage-deflate; client_max_window_bits=15`. Offers it can't agree on are declined.
+    fn negotiate(&mut self, offers: &[ExtensionOffer]) -> Option<ExtensionOffer>;
+    /// Rewrites a frame we received before it's put together into a [`Message`](`crate::message::Message`)
+    fn decode(&mut self, frame: Frame) -> WsGonzaleResult<Frame>;
+    /// Rewrites a frame before it's written to the tcp_stream
+    fn encode(&mut self, frame: Frame) -> WsGonzaleResult<Frame>;
+}
+/// The extensions negotiated for a connection in the order they were accepted
+#[derive(Default)]
+pub struct Extensions(Vec<Box<dyn Extension>>);
+impl Extensions {
+    /// Offers the client's [`ExtensionOffer`]s to every extension in our order of preference.
+    /// Returns the ones that accepted and their responses, an extension that wants RSV bits
+    /// that are already taken isn't asked.
+    pub fn negotiate(
+        extensions: Vec<Box<dyn Extension>>,
+        offers: &[ExtensionOffer],
+    ) -> (Extensions, Vec<ExtensionOffer>) {
+        let mut accepted: Vec<Box<dyn Extension>> = Vec::new();
+        let mut responses: Vec<ExtensionOffer> = Vec::new();
+        let mut rsv = 0;
+        for mut extension in extensions {
+            if rsv & extension.get_rsv() != 0 {
+                continue;
+            }
+            if let Some(response) = extension.negotiate(offers) {
+                rsv |= extension.get_rsv();
+                responses.push(response);
+                accepted.push(extension);
+            }
+        }
+        (Extensions(accepted), responses)
+    }
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+    /// The RSV bits claimed by all the extensions
+    pub fn get_rsv(&self) -> u8 {
+        self.0
+            .iter()
+            .fold(0, |rsv, extension| rsv | extension.get_rsv())
+    }
+    /// Decodes in the reverse order of encoding
+    pub fn decode(&mut self, frame: Frame) -> WsGonzaleResult<Frame> {
+        self.0
+            .iter_mut()
+            .rev()
+            .try_fold(frame, |frame, extension| extension.decode(frame))
+    }
+    pub fn encode(&mut self, frame: Frame) -> WsGonzaleResult<Frame> {
+        self.0
+            .iter_mut()
+            .try_fold(frame, |frame, extension| extension.encode(frame))
+    }
+    /// Encodes a frame from [`get_buffer`](`crate::dataframe::get_buffer`), buffers that aren't a single unmasked frame are returned as they are
+    pub fn encode_buffer(&mut self, buffer: Vec<u8>) -> WsGonzaleResult<Vec<u8>> {
+        if self.is_empty() {
+            return Ok(buffer);
+        }
+        match Frame::from_buffer(buffer) {
+            Ok(frame) => self.encode(frame).map(|frame| frame.get_buffer()),
+            Err(buffer) => Ok(buffer),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{dataframe::get_buffer, message::Message, WsGonzaleError};
+
+    /// Reverses the payload of frames with RSV2 set
+    struct Reverse(&'static str);
+    impl Extension for Reverse {
+        fn get_name(&self) -> &str {
+            self.0
+        }
+        fn get_rsv(&self) -> u8 {
+            frame_positions::RSV2
+        }
+        fn negotiate(&mut self, offers: &[ExtensionOffer]) -> Option<ExtensionOffer> {
+            offers
+                .iter()
+                .any(|offer| offer.name == self.0)
+                .then(|| ExtensionOffer::new(self.0))
+        }
+        fn decode(&mut self, mut frame: Frame) -> WsGonzaleResult<Frame> {
+            if frame.rsv & frame_positions::RSV2 == 0 {
+                return Err(WsGonzaleError::InvalidPayload);
+            }
+            frame.rsv &= !frame_positions::RSV2;
+            frame.payload.reverse();
+            Ok(frame)
+        }
+        fn encode(&mut self, mut frame: Frame) -> WsGonzaleResult<Frame> {
+            frame.rsv |= frame_positions::RSV2;
+            frame.payload.reverse();
+            Ok(frame)
+        }
+    }
+    #[test]
+    fn test_frame_from_buffer() {
+        let buffer = get_buffer(Message::Text("Hi".to_string()));
+        let frame = Frame::from_buffer(buffer.clone()).unwrap();
+        assert_eq!(
+            frame,
+            Frame {
+                fin: true,
+                rsv: 0,
+                opcode: 1,
+                payload: b"Hi".to_vec(),
+            }
+        );
+        assert_eq!(frame.get_buffer(), buffer);
+        // Two frames in one buffer
+        let mut buffers = buffer.clone();
+        buffers.extend_from_slice(&buffer);
+        assert_eq!(Frame::from_buffer(buffers.clone()), Err(buffers));
+    }
+    #[test]
+    fn test_negotiate() {
+        let extensions: Vec<Box<dyn Extension>> = vec![
+            Box::new(Reverse("x-reverse")),
+            Box::new(Reverse("x-unknown")),
+            // Wants RSV2 as well
+            Box::new(Reverse("x-reverse-again")),
+        ];
+        let offers = ExtensionOffer::parse_header("x-reverse-again, x-reverse").unwrap();
+        let (extensions, responses) = Extensions::negotiate(extensions, &offers);
+        assert_eq!(responses, vec![ExtensionOffer::new("x-reverse")]);
+        assert_eq!(extensions.get_rsv(), frame_positions::RSV2);
+        let (extensions, responses) =
+            Extensions::negotiate(vec![Box::new(Reverse("x-reverse"))], &[]);
+        assert!(extensions.is_empty());
+        assert!(responses.is_empty());
+    }
+    #[test]
+    fn test_encode_and_decode() {
+        let (mut extensions, _) = Extensions::negotiate(
+            vec![Box::new(Reverse("x-reverse"))],
+            &[ExtensionOffer::new("x-reverse")],
+        );
+        let buffer = extensions
+            .encode_buffer(get_buffer(Message::Text("Hi".to_string())))
+            .unwrap();
+        // FIN(128) + RSV2(32) + Opcode(1), PayloadLength(2), "iH"
+        assert_eq!(buffer, vec![161, 2, 105, 72]);
+        let frame = extensions
+            .decode(Frame::from_buffer(buffer).unwrap())
+            .unwrap();
+        assert_eq!(frame.rsv, 0);
+        assert_eq!(frame.payload, b"Hi".to_vec());
+    }
+}
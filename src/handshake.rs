@@ -1,61 +1,361 @@
 use {
-    crate::{AsyncResult, WsGonzaleError, WsGonzaleResult},
+    crate::{
+        config::{WsConfig, READ_BUFFER_SIZE},
+        AsyncResult, WsGonzaleError, WsGonzaleResult,
+    },
     async_net::TcpStream,
-    base64::encode,
-    futures::AsyncReadExt,
-    futures::AsyncWriteExt,
+    base64::{decode, encode},
+    futures::{AsyncReadExt, AsyncWriteExt},
     sha1::Sha1,
-    std::collections::HashMap,
-    std::ops::Deref,
-    std::string::ToString,
+    std::{collections::HashMap, ops::Deref, string::ToString, sync::Arc},
 };
 
-const MAGIC_GUID: &'static str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+const MAGIC_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+/// The only `Sec-WebSocket-Version` we speak, the one of RFC 6455
+pub const WEBSOCKET_VERSION: &str = "13";
 
 fn sha1_str(s: &str) -> [u8; 20] {
     let mut sha1 = Sha1::new();
     sha1.update(s.as_bytes());
-    let bytes = sha1.digest().bytes();
-    bytes
+    sha1.digest().bytes()
 }
-fn get_accept_from_key(key: &str) -> Result<String, String> {
+/// The `Sec-WebSocket-Accept` value for the client's `Sec-WebSocket-Key`
+pub fn get_accept_from_key(key: &str) -> String {
     let mut accept_key = String::with_capacity(key.len() + 36);
-    accept_key.push_str(&key);
+    accept_key.push_str(key);
     accept_key.push_str(MAGIC_GUID);
 
     let sha1_accept_key = sha1_str(&accept_key); // sha1 result
-    Ok(encode(sha1_accept_key))
+    encode(sha1_accept_key)
 }
-/// HTTP Request Headers
-#[derive(Debug)]
-pub struct Headers(HashMap<String, String>);
+/// A `Sec-WebSocket-Key` has to be 16 bytes encoded as base64, it's checked as it is so trim it first
+pub fn validate_key(key: &str) -> Result<(), HandshakeError> {
+    match decode(key) {
+        Ok(key) if key.len() == 16 => Ok(()),
+        _ => Err(HandshakeError::InvalidKey),
+    }
+}
+/// Why a request can't be upgraded to a WebSocket connection, see RFC 6455 section 4.2.1
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HandshakeError {
+    /// Not a GET request with a Request-URI
+    InvalidMethod,
+    /// Older than HTTP/1.1
+    InvalidVersion,
+    MissingHost,
+    /// `Upgrade` is missing or isn't `websocket`
+    MissingUpgrade,
+    /// `Connection` is missing or doesn't have the `Upgrade` token
+    MissingConnectionUpgrade,
+    /// `Sec-WebSocket-Key` is missing or isn't 16 bytes encoded as base64
+    InvalidKey,
+    /// `Sec-WebSocket-Version` is missing or isn't 13
+    UnsupportedVersion,
+    /// None of the offered `Sec-WebSocket-Protocol`s is one we speak
+    UnsupportedSubprotocol,
+    /// `Sec-WebSocket-Extensions` doesn't follow the grammar of RFC 6455 section 9.1
+    InvalidExtensions,
+    /// The `Origin` isn't allowed by the [`OriginPolicy`]
+    ForbiddenOrigin,
+}
+impl HandshakeError {
+    /// The HTTP response we reject the request with, `426 Upgrade Required` tells the client which version we speak
+    pub fn get_response(&self) -> Response {
+        match self {
+            HandshakeError::UnsupportedVersion => Response::new(426, "Upgrade Required")
+                .with_header("Sec-WebSocket-Version", WEBSOCKET_VERSION),
+            HandshakeError::ForbiddenOrigin => Response::new(403, "Forbidden"),
+            _ => Response::new(400, "Bad Request"),
+        }
+    }
+}
+/// Which `Origin`s may open a WebSocket, this keeps other websites from connecting with the cookies
+/// of the user visiting them, see RFC 6455 section 10.2. Requests without an `Origin` don't come from
+/// a browser and are allowed unless a [`OriginPolicy::Predicate`] says otherwise.
+#[derive(Clone, Default)]
+pub enum OriginPolicy {
+    /// Every origin, the default
+    #[default]
+    Any,
+    /// Exact origins like `https://example.com`, `https://*.example.com` allows every subdomain of `example.com`
+    AllowList(Vec<String>),
+    /// Decides on the `Origin`, `None` if the request didn't have one
+    Predicate(OriginPredicate),
+}
+/// See [`OriginPolicy::Predicate`]
+pub type OriginPredicate = Arc<dyn Fn(Option<&str>) -> bool + Send + Sync>;
+impl std::fmt::Debug for OriginPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OriginPolicy::Any => write!(f, "Any"),
+            OriginPolicy::AllowList(origins) => f.debug_tuple("AllowList").field(origins).finish(),
+            OriginPolicy::Predicate(_) => write!(f, "Predicate"),
+        }
+    }
+}
+impl OriginPolicy {
+    pub fn allow_list(origins: &[&str]) -> OriginPolicy {
+        OriginPolicy::AllowList(origins.iter().map(|s| s.to_string()).collect())
+    }
+    pub fn predicate(
+        predicate: impl Fn(Option<&str>) -> bool + Send + Sync + 'static,
+    ) -> OriginPolicy {
+        OriginPolicy::Predicate(Arc::new(predicate))
+    }
+    pub fn is_allowed(&self, origin: Option<&str>) -> bool {
+        match (self, origin) {
+            (OriginPolicy::Any, _) => true,
+            (OriginPolicy::Predicate(predicate), origin) => predicate(origin),
+            (OriginPolicy::AllowList(_), None) => true,
+            (OriginPolicy::AllowList(origins), Some(origin)) => {
+                let origin = origin.trim().to_ascii_lowercase();
+                origins
+                    .iter()
+                    .any(|allowed| is_origin_match(&allowed.to_ascii_lowercase(), &origin))
+            }
+        }
+    }
+}
+/// Compares an origin to an entry of [`OriginPolicy::AllowList`], both in lowercase
+fn is_origin_match(allowed: &str, origin: &str) -> bool {
+    match allowed.find("://*.") {
+        // The scheme has to match and the host has to end with `.{domain}`
+        Some(position) => {
+            let (scheme, domain) = (&allowed[..position + 3], &allowed[position + 4..]);
+            match origin.strip_prefix(scheme) {
+                Some(host) => host.len() > domain.len() && host.ends_with(domain),
+                None => false,
+            }
+        }
+        None => allowed == origin,
+    }
+}
+/// An HTTP response to a request we don't upgrade
+#[derive(Clone, Debug, PartialEq)]
+pub struct Response {
+    status: u16,
+    reason: String,
+    headers: Vec<(String, String)>,
+    body: String,
+}
+impl Response {
+    /// e.g. `Response::new(401, "Unauthorized")`, CR and LF are left out of the reason
+    pub fn new(status: u16, reason: &str) -> Response {
+        Response {
+            status,
+            reason: strip_line_breaks(reason),
+            headers: Vec::new(),
+            body: String::new(),
+        }
+    }
+    /// `Content-Length` is added on its own. CR and LF are left out of the value and the name keeps only
+    /// its token characters, so a value taken from the request can't add headers of its own.
+    pub fn with_header(mut self, name: &str, value: &str) -> Response {
+        self.headers.push(get_header(name, value));
+        self
+    }
+    pub fn with_body(mut self, body: &str) -> Response {
+        self.body = body.to_string();
+        self
+    }
+    pub fn get_status(&self) -> u16 {
+        self.status
+    }
+    pub fn get_headers(&self) -> &[(String, String)] {
+        &self.headers
+    }
+    pub fn get_body(&self) -> &str {
+        &self.body
+    }
+}
+impl std::fmt::Display for Response {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "HTTP/1.1 {} {}\r\n", self.status, self.reason)?;
+        for (name, value) in &self.headers {
+            write!(f, "{}", get_header_line(name, value))?;
+        }
+        write!(
+            f,
+            "Content-Length: {}\r\n\r\n{}",
+            self.body.len(),
+            self.body
+        )
+    }
+}
+/// What a handshake callback decides about a [`Request`] that is a valid WebSocket handshake,
+/// see [`WsConnection::upgrade_with_callback`](`crate::connection::WsConnection::upgrade_with_callback`)
+#[derive(Clone, Debug, PartialEq)]
+pub enum HandshakeDecision<T> {
+    /// Upgrade with extra headers in the `101 Switching Protocols` response, e.g. `Set-Cookie`,
+    /// and data that goes with this connection, e.g. the user who logged in
+    Accept {
+        headers: Vec<(String, String)>,
+        data: T,
+    },
+    /// Answer with this response instead of upgrading
+    Reject(Response),
+}
+impl<T> HandshakeDecision<T> {
+    pub fn accept(data: T) -> HandshakeDecision<T> {
+        HandshakeDecision::Accept {
+            headers: Vec::new(),
+            data,
+        }
+    }
+    /// Adds a header to the response of an [`HandshakeDecision::Accept`], it's cleaned up like in [`Response::with_header`]
+    pub fn with_header(mut self, name: &str, value: &str) -> HandshakeDecision<T> {
+        if let HandshakeDecision::Accept { headers, .. } = &mut self {
+            headers.push(get_header(name, value));
+        }
+        self
+    }
+    pub fn reject(response: Response) -> HandshakeDecision<T> {
+        HandshakeDecision::Reject(response)
+    }
+    /// Changes the data of an [`HandshakeDecision::Accept`]
+    pub fn map<U>(self, f: impl FnOnce(T) -> U) -> HandshakeDecision<U> {
+        match self {
+            HandshakeDecision::Accept { headers, data } => HandshakeDecision::Accept {
+                headers,
+                data: f(data),
+            },
+            HandshakeDecision::Reject(response) => HandshakeDecision::Reject(response),
+        }
+    }
+}
+/// The `101 Switching Protocols` response accepting the client's `Sec-WebSocket-Key`,
+/// with extra headers e.g. the negotiated `Sec-WebSocket-Extensions`
+pub fn get_handshake_response(key: &str, headers: &[(String, String)]) -> String {
+    let mut response = format!("HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-Websocket-Accept: {accept_key}\r\n", accept_key = get_accept_from_key(key));
+    for (name, value) in headers {
+        response.push_str(&get_header_line(name, value));
+    }
+    response.push_str("\r\n");
+    response
+}
+/// Writes `name: value\r\n` cleaned up like in [`Response::with_header`], the headers of a [`HandshakeDecision::Accept`] can be built by hand
+fn get_header_line(name: &str, value: &str) -> String {
+    let (name, value) = get_header(name, value);
+    format!("{}: {}\r\n", name, value)
+}
+fn get_header(name: &str, value: &str) -> (String, String) {
+    let name = name
+        .chars()
+        .filter(|c| c.is_ascii() && is_token_byte(*c as u8));
+    (name.collect(), strip_line_breaks(value))
+}
+fn strip_line_breaks(s: &str) -> String {
+    s.chars().filter(|c| *c != '\r' && *c != '\n').collect()
+}
+/// HTTP Request Headers, names are case-insensitive and a repeated header keeps all of its values in order
+#[derive(Debug, Default)]
+pub struct Headers(HashMap<String, Vec<String>>);
 
 impl Headers {
     pub fn new(headers: HashMap<String, String>) -> Self {
-        Self(headers)
+        let mut new_headers = Headers::default();
+        for (key, value) in headers {
+            new_headers.insert(&key, &value);
+        }
+        new_headers
     }
+    /// Adds a value after the ones the header already has
+    pub fn insert(&mut self, key: &str, value: &str) {
+        self.0
+            .entry(key.trim().to_ascii_lowercase())
+            .or_default()
+            .push(value.trim().to_string());
+    }
+    /// The first value of the header
     pub fn get(&self, key: &str) -> Option<&String> {
-        self.0.get(key)
+        self.get_all(key).first()
+    }
+    /// Every value of a repeated header
+    pub fn get_all(&self, key: &str) -> &[String] {
+        self.0
+            .get(&key.to_ascii_lowercase())
+            .map(|values| values.as_slice())
+            .unwrap_or(&[])
+    }
+    /// The comma-separated tokens of every value, e.g. `keep-alive` and `Upgrade` of `Connection: keep-alive, Upgrade`
+    pub fn get_tokens(&self, key: &str) -> Vec<&str> {
+        self.get_all(key)
+            .iter()
+            .flat_map(|value| value.split(','))
+            .map(|token| token.trim())
+            .filter(|token| !token.is_empty())
+            .collect()
+    }
+    /// Whether one of the tokens of the header is `token`, compared case-insensitively
+    pub fn has_token(&self, key: &str, token: &str) -> bool {
+        self.get_tokens(key)
+            .iter()
+            .any(|value| value.eq_ignore_ascii_case(token))
+    }
+    /// The number of different headers
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
     }
-}
-/// Quickly writes a response to the TcpStream with a valid `Sec-Websocket-Accept: {key}` if available
-pub async fn handshake(key: &str, tcp_stream: &mut TcpStream) -> AsyncResult<()> {
-    let accept_key = get_accept_from_key(&key).unwrap_or("".to_string());
-    // Just a quick reply with the `Sec-Websocket-Accept: {key}`
-    let returned_string = format!("HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-Websocket-Accept: {accept_key}\r\n\r\n", accept_key = accept_key);
-    tcp_stream.write_all(returned_string.as_bytes()).await?; // Accept the connection
-    Ok(())
 }
 
 /// HTTP Methods
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum HTTPMethod {
     GET,
+    HEAD,
     POST,
+    PUT,
+    PATCH,
     DELETE,
+    OPTIONS,
+    CONNECT,
+    TRACE,
+    /// A method that isn't one of the above, e.g. `PROPFIND`
     Unknown,
 }
-/// HTTP Request URI
+impl HTTPMethod {
+    pub fn new(method: &str) -> HTTPMethod {
+        match method {
+            "GET" => HTTPMethod::GET,
+            "HEAD" => HTTPMethod::HEAD,
+            "POST" => HTTPMethod::POST,
+            "PUT" => HTTPMethod::PUT,
+            "PATCH" => HTTPMethod::PATCH,
+            "DELETE" => HTTPMethod::DELETE,
+            "OPTIONS" => HTTPMethod::OPTIONS,
+            "CONNECT" => HTTPMethod::CONNECT,
+            "TRACE" => HTTPMethod::TRACE,
+            _ => HTTPMethod::Unknown,
+        }
+    }
+    /// The method as it's written in the request line, `None` for [`HTTPMethod::Unknown`]
+    pub fn as_str(&self) -> Option<&'static str> {
+        let method = match self {
+            HTTPMethod::GET => "GET",
+            HTTPMethod::HEAD => "HEAD",
+            HTTPMethod::POST => "POST",
+            HTTPMethod::PUT => "PUT",
+            HTTPMethod::PATCH => "PATCH",
+            HTTPMethod::DELETE => "DELETE",
+            HTTPMethod::OPTIONS => "OPTIONS",
+            HTTPMethod::CONNECT => "CONNECT",
+            HTTPMethod::TRACE => "TRACE",
+            HTTPMethod::Unknown => return None,
+        };
+        Some(method)
+    }
+    /// Whether a request with this method keeps its body
+    fn has_body(&self) -> bool {
+        matches!(
+            self,
+            HTTPMethod::POST | HTTPMethod::PUT | HTTPMethod::PATCH | HTTPMethod::DELETE
+        )
+    }
+}
+/// HTTP Request URI, e.g. `/rooms/42?token=abc`
 #[derive(Debug)]
 pub struct Uri(String);
 impl Deref for Uri {
@@ -65,25 +365,142 @@ impl Deref for Uri {
         self.0.as_str()
     }
 }
+impl Uri {
+    pub fn new(uri: &str) -> Uri {
+        Uri(uri.to_string())
+    }
+    /// The path before it's decoded, the scheme and host of an absolute URI are left out
+    pub fn get_raw_path(&self) -> &str {
+        let mut uri = self.0.as_str();
+        for scheme in ["http://", "https://", "ws://", "wss://"].iter() {
+            if let Some(rest) = uri.strip_prefix(scheme) {
+                uri = rest.find('/').map(|start| &rest[start..]).unwrap_or("/");
+                break;
+            }
+        }
+        let end = uri.find(['?', '#']).unwrap_or(uri.len());
+        &uri[..end]
+    }
+    /// The query string without the `?`, `None` if there is none
+    pub fn get_raw_query(&self) -> Option<&str> {
+        let uri = self.0.split('#').next().unwrap_or("");
+        uri.find('?').map(|start| &uri[start + 1..])
+    }
+    /// The percent-decoded path, e.g. `/rooms/my room` for `/rooms/my%20room`
+    pub fn get_path(&self) -> Result<String, UriError> {
+        percent_decode(self.get_raw_path(), false)
+    }
+    /// The percent-decoded segments of the path without the empty ones, e.g. `["rooms", "42"]` for `/rooms/42/`.
+    /// An encoded `/` (`%2F`) stays inside its segment.
+    pub fn get_segments(&self) -> Result<Vec<String>, UriError> {
+        self.get_raw_path()
+            .split('/')
+            .filter(|segment| !segment.is_empty())
+            .map(|segment| percent_decode(segment, false))
+            .collect()
+    }
+    /// The percent-decoded parameters of the query string, `+` is decoded to a space
+    pub fn get_query(&self) -> Result<Query, UriError> {
+        let mut params = Vec::new();
+        for param in self.get_raw_query().unwrap_or("").split('&') {
+            if param.is_empty() {
+                continue;
+            }
+            let mut splits = param.splitn(2, '=');
+            let key = percent_decode(splits.next().unwrap_or(""), true)?;
+            let value = percent_decode(splits.next().unwrap_or(""), true)?;
+            params.push((key, value));
+        }
+        Ok(Query(params))
+    }
+}
+/// Why a [`Uri`] couldn't be decoded
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum UriError {
+    /// A `%` that isn't followed by two hex digits
+    InvalidPercentEncoding,
+    /// The decoded bytes aren't valid UTF-8
+    InvalidUtf8,
+}
+/// The parameters of a query string in the order they came, a key can be repeated like `?tag=a&tag=b`
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Query(Vec<(String, String)>);
+impl Query {
+    /// The first value of `key`, a key without `=` has an empty value
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(name, _)| name == key)
+            .map(|(_, value)| value.as_str())
+    }
+    /// Every value of `key`
+    pub fn get_all(&self, key: &str) -> Vec<&str> {
+        self.0
+            .iter()
+            .filter(|(name, _)| name == key)
+            .map(|(_, value)| value.as_str())
+            .collect()
+    }
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.0
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_str()))
+    }
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+/// Decodes `%XX` escapes, and `+` to a space in a query string
+fn percent_decode(s: &str, is_query: bool) -> Result<String, UriError> {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut position = 0;
+    while position < bytes.len() {
+        match bytes[position] {
+            b'%' => {
+                let hex = bytes
+                    .get(position + 1..position + 3)
+                    .and_then(|hex| std::str::from_utf8(hex).ok())
+                    .filter(|hex| hex.bytes().all(|byte| byte.is_ascii_hexdigit()))
+                    .ok_or(UriError::InvalidPercentEncoding)?;
+                decoded.push(
+                    u8::from_str_radix(hex, 16).map_err(|_| UriError::InvalidPercentEncoding)?,
+                );
+                position += 3;
+            }
+            b'+' if is_query => {
+                decoded.push(b' ');
+                position += 1;
+            }
+            byte => {
+                decoded.push(byte);
+                position += 1;
+            }
+        }
+    }
+    String::from_utf8(decoded).map_err(|_| UriError::InvalidUtf8)
+}
 /// HTTP Request Endpoints
 #[derive(Debug)]
 pub struct Endpoint {
     method: HTTPMethod,
     uri: Uri,
+    /// e.g. `HTTP/1.1`
+    version: String,
 }
 impl Endpoint {
     pub fn new(s: &str) -> Endpoint {
         let mut splits = s.split(" ");
         Endpoint {
-            method: match splits.next().unwrap_or("") {
-                "GET" => HTTPMethod::GET,
-                "POST" => HTTPMethod::POST,
-                _ => HTTPMethod::Unknown,
-            },
+            method: HTTPMethod::new(splits.next().unwrap_or("")),
             uri: Uri(splits
                 .next()
                 .map(|s| s.to_string())
                 .unwrap_or("".to_string())),
+            version: splits.next().unwrap_or("").trim().to_string(),
         }
     }
     pub fn get_method(&self) -> &HTTPMethod {
@@ -92,6 +509,22 @@ impl Endpoint {
     pub fn get_uri(&self) -> &Uri {
         &self.uri
     }
+    pub fn get_version(&self) -> &str {
+        &self.version
+    }
+    /// HTTP/1.1 or later, which a WebSocket handshake requires
+    fn is_http_1_1(&self) -> bool {
+        let version = match self.version.strip_prefix("HTTP/") {
+            Some(version) => version,
+            None => return false,
+        };
+        let mut numbers = version.splitn(2, '.').map(|number| number.parse::<u32>());
+        match (numbers.next(), numbers.next()) {
+            (Some(Ok(major)), Some(Ok(minor))) => (major, minor) >= (1, 1),
+            (Some(Ok(major)), None) => major >= 2,
+            _ => false,
+        }
+    }
 }
 /// HTTP Request Body
 #[derive(Debug, PartialEq)]
@@ -108,8 +541,11 @@ pub struct Request {
     endpoint: Endpoint,
     headers: Headers,
     body: Option<Body>,
+    /// Bytes that arrived after the request
+    remaining: Vec<u8>,
 }
 impl Request {
+    #[allow(clippy::should_implement_trait)]
     pub fn from_str(s: &str) -> WsGonzaleResult<Request> {
         let mut request = s.lines().collect::<Vec<&str>>();
         let empty_index = request.iter().position(|s| s.is_empty());
@@ -126,45 +562,30 @@ impl Request {
         }
         let endpoint = Endpoint::new(endpoint.unwrap());
 
-        let headers = {
-            let headers: HashMap<String, String> = iters
-                .flat_map(|val| {
-                    let mut splits = val.splitn(2, ": ");
-                    match (splits.next(), splits.next()) {
-                        (Some(key), Some(value)) => Some((key.to_string(), value.to_string())),
-                        _ => None,
-                    }
-                })
-                .collect();
-
-            Headers::new(headers)
-        };
+        let mut headers = Headers::default();
+        for val in iters {
+            let mut splits = val.splitn(2, ':');
+            if let (Some(key), Some(value)) = (splits.next(), splits.next()) {
+                headers.insert(key, value);
+            }
+        }
 
         let data = match (&endpoint.method, body) {
-            (HTTPMethod::POST, body @ Some(_)) => Request {
+            (method, body @ Some(_)) if method.has_body() => Request {
                 endpoint,
                 headers,
                 body,
+                remaining: Vec::new(),
             },
             _ => Request {
                 endpoint,
                 headers,
                 body: None,
+                remaining: Vec::new(),
             },
         };
         Ok(data)
     }
-    pub async fn read_from_stream(tcp_stream: &mut TcpStream) -> WsGonzaleResult<Request> {
-        let mut buffers: Vec<u8> = vec![0u8; 100000];
-        let number = match tcp_stream.read(&mut buffers).await {
-            Ok(n) if n == 0 => return Err(WsGonzaleError::Unknown),
-            Ok(n) => n,
-            Err(_err) => return Err(WsGonzaleError::Unknown),
-        };
-        let buffers: Vec<u8> = buffers.into_iter().take(number).collect();
-        let s = String::from_utf8_lossy(&buffers).to_string();
-        Request::from_str(&s)
-    }
     pub fn get_endpoint(&self) -> &Endpoint {
         &self.endpoint
     }
@@ -174,11 +595,714 @@ impl Request {
     pub fn get_body(&self) -> Option<&Body> {
         self.body.as_ref()
     }
+    /// Bytes [`RequestDecoder`] got after the end of the request, e.g. the first frames of a WebSocket client
+    pub fn get_remaining(&self) -> &[u8] {
+        &self.remaining
+    }
+    /// Checks every requirement of RFC 6455 section 4.2.1 for upgrading the request and returns the `Sec-WebSocket-Key`
+    pub fn validate_handshake(&self) -> Result<&str, HandshakeError> {
+        if self.endpoint.method != HTTPMethod::GET || self.endpoint.uri.is_empty() {
+            return Err(HandshakeError::InvalidMethod);
+        }
+        if !self.endpoint.is_http_1_1() {
+            return Err(HandshakeError::InvalidVersion);
+        }
+        if self.headers.get("Host").is_none() {
+            return Err(HandshakeError::MissingHost);
+        }
+        if !self.headers.has_token("Upgrade", "websocket") {
+            return Err(HandshakeError::MissingUpgrade);
+        }
+        if !self.headers.has_token("Connection", "Upgrade") {
+            return Err(HandshakeError::MissingConnectionUpgrade);
+        }
+        let key = self
+            .headers
+            .get("Sec-WebSocket-Key")
+            .ok_or(HandshakeError::InvalidKey)?;
+        validate_key(key)?;
+        match self.headers.get("Sec-WebSocket-Version") {
+            Some(version) if version == WEBSOCKET_VERSION => Ok(key),
+            _ => Err(HandshakeError::UnsupportedVersion),
+        }
+    }
+    /// Picks the first of our `subprotocols` the client offered in `Sec-WebSocket-Protocol`,
+    /// `None` if we or the client don't use subprotocols
+    pub fn negotiate_subprotocol(
+        &self,
+        subprotocols: &[String],
+    ) -> Result<Option<String>, HandshakeError> {
+        let offers = self.get_subprotocols();
+        if subprotocols.is_empty() || offers.is_empty() {
+            return Ok(None);
+        }
+        subprotocols
+            .iter()
+            .find(|subprotocol| offers.contains(&subprotocol.as_str()))
+            .cloned()
+            .map(Some)
+            .ok_or(HandshakeError::UnsupportedSubprotocol)
+    }
+    /// Checks the `Origin` of the request against `policy`
+    pub fn validate_origin(&self, policy: &OriginPolicy) -> Result<(), HandshakeError> {
+        if policy.is_allowed(self.headers.get("Origin").map(|origin| origin.as_str())) {
+            Ok(())
+        } else {
+            Err(HandshakeError::ForbiddenOrigin)
+        }
+    }
+    /// The subprotocols the client offered in `Sec-WebSocket-Protocol`, in its order of preference
+    pub fn get_subprotocols(&self) -> Vec<&str> {
+        self.headers.get_tokens("Sec-WebSocket-Protocol")
+    }
+    /// The extensions the client offered in `Sec-WebSocket-Extensions`, in its order of preference
+    pub fn get_extension_offers(&self) -> Result<Vec<ExtensionOffer>, HandshakeError> {
+        let mut offers = Vec::new();
+        for header in self.headers.get_all("Sec-WebSocket-Extensions") {
+            offers.extend(ExtensionOffer::parse_header(header)?);
+        }
+        Ok(offers)
+    }
+}
+/// A parameter of an [`ExtensionOffer`], e.g. `client_max_window_bits=10`
+#[derive(Clone, Debug, PartialEq)]
+pub struct ExtensionParam {
+    pub name: String,
+    /// Without the quotes if it was quoted, `None` if the parameter has no value
+    pub value: Option<String>,
+}
+/// An extension with its parameters in `Sec-WebSocket-Extensions`, either an offer of the client or the
+/// response we accept it with, e.g. `permessage-deflate; client_max_window_bits`
+#[derive(Clone, Debug, PartialEq)]
+pub struct ExtensionOffer {
+    pub name: String,
+    /// In the order they were sent, a parameter can be repeated
+    pub params: Vec<ExtensionParam>,
+}
+impl ExtensionOffer {
+    pub fn new(name: &str) -> ExtensionOffer {
+        ExtensionOffer {
+            name: name.to_string(),
+            params: Vec::new(),
+        }
+    }
+    pub fn with_param(mut self, name: &str, value: Option<&str>) -> ExtensionOffer {
+        self.params.push(ExtensionParam {
+            name: name.to_string(),
+            value: value.map(|value| value.to_string()),
+        });
+        self
+    }
+    /// The first parameter called `name`
+    pub fn get_param(&self, name: &str) -> Option<&ExtensionParam> {
+        self.params.iter().find(|param| param.name == name)
+    }
+    /// Parses the comma-separated offers of one `Sec-WebSocket-Extensions` header, see RFC 6455 section 9.1
+    pub fn parse_header(header: &str) -> Result<Vec<ExtensionOffer>, HandshakeError> {
+        let mut parser = ExtensionParser {
+            input: header.as_bytes(),
+            position: 0,
+        };
+        let mut offers = Vec::new();
+        loop {
+            parser.skip_whitespace();
+            // Empty elements of the list are allowed and skipped
+            match parser.peek() {
+                None => return Ok(offers),
+                Some(b',') => {
+                    parser.position += 1;
+                    continue;
+                }
+                Some(_) => {}
+            }
+            let mut offer = ExtensionOffer::new(&parser.token()?);
+            parser.skip_whitespace();
+            while parser.peek() == Some(b';') {
+                parser.position += 1;
+                parser.skip_whitespace();
+                let name = parser.token()?;
+                parser.skip_whitespace();
+                let value = if parser.peek() == Some(b'=') {
+                    parser.position += 1;
+                    parser.skip_whitespace();
+                    Some(parser.value()?)
+                } else {
+                    None
+                };
+                offer.params.push(ExtensionParam { name, value });
+                parser.skip_whitespace();
+            }
+            offers.push(offer);
+            match parser.peek() {
+                None => return Ok(offers),
+                Some(b',') => parser.position += 1,
+                Some(_) => return Err(HandshakeError::InvalidExtensions),
+            }
+        }
+    }
+}
+impl std::fmt::Display for ExtensionOffer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name)?;
+        for param in &self.params {
+            write!(f, "; {}", param.name)?;
+            match &param.value {
+                Some(value) if is_token(value) => write!(f, "={}", value)?,
+                Some(value) => write!(
+                    f,
+                    "=\"{}\"",
+                    value.replace('\\', "\\\\").replace('"', "\\\"")
+                )?,
+                None => {}
+            }
+        }
+        Ok(())
+    }
+}
+/// Reads the tokens and quoted strings of a `Sec-WebSocket-Extensions` header
+struct ExtensionParser<'a> {
+    input: &'a [u8],
+    position: usize,
+}
+impl ExtensionParser<'_> {
+    fn peek(&self) -> Option<u8> {
+        self.input.get(self.position).copied()
+    }
+    fn skip_whitespace(&mut self) {
+        while let Some(b' ') | Some(b'\t') = self.peek() {
+            self.position += 1;
+        }
+    }
+    fn token(&mut self) -> Result<String, HandshakeError> {
+        let start = self.position;
+        while self.peek().map(is_token_byte).unwrap_or(false) {
+            self.position += 1;
+        }
+        if start == self.position {
+            return Err(HandshakeError::InvalidExtensions);
+        }
+        Ok(String::from_utf8_lossy(&self.input[start..self.position]).to_string())
+    }
+    /// A token or a quoted string, which has to be a token once it's unquoted
+    fn value(&mut self) -> Result<String, HandshakeError> {
+        if self.peek() != Some(b'"') {
+            return self.token();
+        }
+        self.position += 1;
+        let mut value = Vec::new();
+        loop {
+            match self.peek() {
+                None => return Err(HandshakeError::InvalidExtensions),
+                Some(b'"') => break,
+                Some(b'\\') => {
+                    self.position += 1;
+                    value.push(self.peek().ok_or(HandshakeError::InvalidExtensions)?);
+                }
+                Some(byte) => value.push(byte),
+            }
+            self.position += 1;
+        }
+        self.position += 1;
+        let value = String::from_utf8_lossy(&value).to_string();
+        if is_token(&value) {
+            Ok(value)
+        } else {
+            Err(HandshakeError::InvalidExtensions)
+        }
+    }
+}
+/// Whether a byte may be part of a token, see RFC 7230 section 3.2.6
+fn is_token_byte(byte: u8) -> bool {
+    byte.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&byte)
+}
+fn is_token(s: &str) -> bool {
+    !s.is_empty() && s.bytes().all(is_token_byte)
+}
+/// Largest request line and headers we accept by default, 16 KiB
+pub const DEFAULT_MAX_HEADER_SIZE: usize = 16 << 10;
+/// Largest request body we accept by default, 1 MiB
+pub const DEFAULT_MAX_BODY_SIZE: usize = 1 << 20;
+
+/// Turns the bytes of an HTTP request into a [`Request`] without doing any IO itself, like
+/// [`FrameDecoder`](`crate::decoder::FrameDecoder`) does for frames. The headers end at the first empty line and are followed by
+/// `Content-Length` bytes of body.
+#[derive(Clone, Debug)]
+pub struct RequestDecoder {
+    buffer: Vec<u8>,
+    max_header_size: usize,
+    max_body_size: usize,
+}
+impl Default for RequestDecoder {
+    fn default() -> Self {
+        RequestDecoder {
+            buffer: Vec::new(),
+            max_header_size: DEFAULT_MAX_HEADER_SIZE,
+            max_body_size: DEFAULT_MAX_BODY_SIZE,
+        }
+    }
+}
+impl RequestDecoder {
+    pub fn new() -> RequestDecoder {
+        RequestDecoder::default()
+    }
+    /// The request line and headers together, including the empty line they end with
+    pub fn with_max_header_size(mut self, max_header_size: usize) -> RequestDecoder {
+        self.max_header_size = max_header_size;
+        self
+    }
+    pub fn with_max_body_size(mut self, max_body_size: usize) -> RequestDecoder {
+        self.max_body_size = max_body_size;
+        self
+    }
+    /// Appends a chunk of bytes, it can be any size and doesn't have to line up with the request
+    pub fn extend(&mut self, bytes: &[u8]) {
+        self.buffer.extend_from_slice(bytes);
+    }
+    /// Returns the [`Request`] once its headers and body have arrived, `None` means we need more bytes.
+    /// Everything after the request is handed over in [`Request::get_remaining`].
+    pub fn next_request(&mut self) -> WsGonzaleResult<Option<Request>> {
+        let header_end = match self
+            .buffer
+            .windows(4)
+            .position(|window| window == b"\r\n\r\n")
+        {
+            Some(header_end) => header_end,
+            None if self.buffer.len() > self.max_header_size => {
+                return Err(WsGonzaleError::HeadersTooLarge)
+            }
+            None => return Ok(None),
+        };
+        let header_length = header_end + 4;
+        if header_length > self.max_header_size {
+            return Err(WsGonzaleError::HeadersTooLarge);
+        }
+        let head = std::str::from_utf8(&self.buffer[..header_end])
+            .map_err(|_| WsGonzaleError::InvalidPayload)?;
+        let mut request = Request::from_str(head)?;
+        let body_length = match request.headers.get("Content-Length") {
+            Some(length) => length
+                .parse::<usize>()
+                .map_err(|_| WsGonzaleError::InvalidPayload)?,
+            None => 0,
+        };
+        if body_length > self.max_body_size {
+            return Err(WsGonzaleError::RequestTooLarge);
+        }
+        if self.buffer.len() - header_length < body_length {
+            return Ok(None);
+        }
+        let mut remaining = std::mem::take(&mut self.buffer).split_off(header_length);
+        let body = remaining.drain(..body_length).collect::<Vec<u8>>();
+        if !body.is_empty() {
+            let body = String::from_utf8(body).map_err(|_| WsGonzaleError::InvalidPayload)?;
+            request.body = Some(Body(body));
+        }
+        request.remaining = remaining;
+        Ok(Some(request))
+    }
+}
+/// Quickly writes a response to the TcpStream with a valid `Sec-Websocket-Accept: {key}`,
+/// an invalid key is answered with `400 Bad Request` instead.
+/// Use [`Request::validate_handshake`] first to check the rest of the request.
+#[deprecated(note = "only checks the key, use `WsConnection::upgrade_from_request` instead")]
+pub async fn handshake(key: &str, tcp_stream: &mut TcpStream) -> AsyncResult<()> {
+    Ok(handshake_with_headers(key, &[], tcp_stream).await?)
+}
+/// Same as [`handshake`] with extra headers in the response, e.g. the negotiated `Sec-WebSocket-Extensions`.
+/// The request has to be validated first, see [`WsConnection::upgrade_from_request`](`crate::connection::WsConnection::upgrade_from_request`).
+pub(crate) async fn handshake_with_headers(
+    key: &str,
+    headers: &[(String, String)],
+    tcp_stream: &mut TcpStream,
+) -> WsGonzaleResult<()> {
+    // The same key is validated and hashed into the Sec-WebSocket-Accept
+    let key = key.trim();
+    if let Err(error) = validate_key(key) {
+        reject(error, tcp_stream).await?;
+        return Err(WsGonzaleError::InvalidHandshake(error));
+    }
+    let response = get_handshake_response(key, headers);
+    tcp_stream.write_all(response.as_bytes()).await?; // Accept the connection
+    Ok(())
+}
+/// Answers a request we can't upgrade with the HTTP error response for it
+pub async fn reject(error: HandshakeError, tcp_stream: &mut TcpStream) -> AsyncResult<()> {
+    respond(&error.get_response(), tcp_stream).await
+}
+/// Writes a [`Response`] to a request we don't upgrade
+pub async fn respond(response: &Response, tcp_stream: &mut TcpStream) -> AsyncResult<()> {
+    tcp_stream.write_all(response.to_string().as_bytes()).await
+}
+impl Request {
+    /// Reads until the whole request has arrived, what the client sent after it is kept in [`Request::get_remaining`].
+    /// A request that's too large or malformed is answered with [`WsGonzaleError::get_response`] before the error is returned.
+    pub async fn read_from_stream(tcp_stream: &mut TcpStream) -> WsGonzaleResult<Request> {
+        Request::read_with_decoder(tcp_stream, RequestDecoder::new()).await
+    }
+    /// Same as [`Request::read_from_stream`] with the request size limits of a [`WsConfig`]
+    pub async fn read_from_stream_with_config(
+        tcp_stream: &mut TcpStream,
+        config: &WsConfig,
+    ) -> WsGonzaleResult<Request> {
+        let decoder = RequestDecoder::new()
+            .with_max_header_size(config.get_max_header_size())
+            .with_max_body_size(config.get_max_body_size());
+        Request::read_with_decoder(tcp_stream, decoder).await
+    }
+    async fn read_with_decoder(
+        tcp_stream: &mut TcpStream,
+        mut decoder: RequestDecoder,
+    ) -> WsGonzaleResult<Request> {
+        let mut buffer = [0u8; READ_BUFFER_SIZE];
+        loop {
+            match decoder.next_request() {
+                Ok(Some(request)) => return Ok(request),
+                Ok(None) => {}
+                Err(error) => {
+                    if let Some(response) = error.get_response() {
+                        // The error is what we return even if the client is already gone
+                        let _ = respond(&response, tcp_stream).await;
+                    }
+                    return Err(error);
+                }
+            }
+            match tcp_stream.read(&mut buffer).await? {
+                // The client hung up before the request was complete
+                0 => return Err(WsGonzaleError::ConnectionClosed),
+                n => decoder.extend(&buffer[..n]),
+            }
+        }
+    }
 }
+
 #[cfg(test)]
 mod tests {
     use super::*;
     #[test]
+    fn test_get_accept_from_key() {
+        // The example from RFC 6455 section 1.3
+        assert_eq!(
+            get_accept_from_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+        let response = get_handshake_response(
+            "dGhlIHNhbXBsZSBub25jZQ==",
+            &[(
+                "Sec-WebSocket-Extensions".to_string(),
+                "permessage-deflate".to_string(),
+            )],
+        );
+        assert!(response.starts_with("HTTP/1.1 101 Switching Protocols\r\n"));
+        assert!(response.ends_with("Sec-WebSocket-Extensions: permessage-deflate\r\n\r\n"));
+    }
+    /// The handshake from RFC 6455 section 1.2 with a header replaced or removed
+    fn handshake_request(name: &str, value: Option<&str>) -> Request {
+        let mut request = "GET /chat HTTP/1.1\r\n".to_string();
+        let headers = [
+            ("Host", "server.example.com"),
+            ("Upgrade", "websocket"),
+            ("Connection", "Upgrade"),
+            ("Sec-WebSocket-Key", "dGhlIHNhbXBsZSBub25jZQ=="),
+            ("Sec-WebSocket-Version", "13"),
+        ];
+        for (header, default) in headers.iter() {
+            let value = if *header == name {
+                value
+            } else {
+                Some(*default)
+            };
+            if let Some(value) = value {
+                request.push_str(&format!("{}: {}\r\n", header, value));
+            }
+        }
+        request.push_str("\r\n");
+        Request::from_str(&request).unwrap()
+    }
+    #[test]
+    fn test_validate_handshake() {
+        let request = handshake_request("", None);
+        assert_eq!(request.validate_handshake(), Ok("dGhlIHNhbXBsZSBub25jZQ=="));
+        let request = handshake_request("Connection", Some("keep-alive, upgrade"));
+        assert!(request.validate_handshake().is_ok());
+        let request = handshake_request("Upgrade", Some("WebSocket"));
+        assert!(request.validate_handshake().is_ok());
+        let cases = [
+            ("Host", None, HandshakeError::MissingHost),
+            ("Upgrade", Some("h2c"), HandshakeError::MissingUpgrade),
+            (
+                "Connection",
+                Some("keep-alive"),
+                HandshakeError::MissingConnectionUpgrade,
+            ),
+            ("Sec-WebSocket-Key", None, HandshakeError::InvalidKey),
+            (
+                "Sec-WebSocket-Key",
+                Some("c2hvcnQ="),
+                HandshakeError::InvalidKey,
+            ),
+            (
+                "Sec-WebSocket-Version",
+                Some("8"),
+                HandshakeError::UnsupportedVersion,
+            ),
+            (
+                "Sec-WebSocket-Version",
+                None,
+                HandshakeError::UnsupportedVersion,
+            ),
+        ];
+        for (name, value, error) in cases.iter() {
+            let request = handshake_request(name, *value);
+            assert_eq!(request.validate_handshake(), Err(*error), "{}", name);
+        }
+    }
+    #[test]
+    fn test_negotiate_subprotocol() {
+        let request =
+            Request::from_str("GET / HTTP/1.1\r\nSec-WebSocket-Protocol: chat.v1, chat.v2\r\n\r\n")
+                .unwrap();
+        assert_eq!(request.get_subprotocols(), vec!["chat.v1", "chat.v2"]);
+        let subprotocols = vec!["chat.v2".to_string(), "chat.v1".to_string()];
+        assert_eq!(
+            request.negotiate_subprotocol(&subprotocols),
+            Ok(Some("chat.v2".to_string()))
+        );
+        assert_eq!(request.negotiate_subprotocol(&[]), Ok(None));
+        assert_eq!(
+            request.negotiate_subprotocol(&["graphql-transport-ws".to_string()]),
+            Err(HandshakeError::UnsupportedSubprotocol)
+        );
+        let request = handshake_request("", None);
+        assert_eq!(request.negotiate_subprotocol(&subprotocols), Ok(None));
+    }
+    #[test]
+    fn test_parse_extension_offers() {
+        let offers = ExtensionOffer::parse_header(
+            "permessage-deflate; client_max_window_bits=\"10\"; server_no_context_takeover ,, x-webkit-deflate-frame",
+        )
+        .unwrap();
+        assert_eq!(
+            offers,
+            vec![
+                ExtensionOffer::new("permessage-deflate")
+                    .with_param("client_max_window_bits", Some("10"))
+                    .with_param("server_no_context_takeover", None),
+                ExtensionOffer::new("x-webkit-deflate-frame"),
+            ]
+        );
+        assert_eq!(
+            offers[0].to_string(),
+            "permessage-deflate; client_max_window_bits=10; server_no_context_takeover"
+        );
+        assert_eq!(ExtensionOffer::parse_header(""), Ok(Vec::new()));
+        let invalid = [
+            "permessage-deflate; ",
+            "permessage-deflate; a=",
+            "permessage-deflate; a=\"1 0\"",
+            "permessage-deflate; a=\"10",
+            "permessage-deflate a",
+            "; a",
+        ];
+        for header in invalid.iter() {
+            assert_eq!(
+                ExtensionOffer::parse_header(header),
+                Err(HandshakeError::InvalidExtensions),
+                "{}",
+                header
+            );
+        }
+    }
+    #[test]
+    fn test_origin_policy() {
+        let policy = OriginPolicy::allow_list(&["https://example.com", "https://*.Example.org"]);
+        assert!(policy.is_allowed(Some("https://example.com")));
+        assert!(policy.is_allowed(Some("HTTPS://chat.example.org")));
+        assert!(policy.is_allowed(None));
+        let forbidden = [
+            "http://example.com",
+            "https://example.com.evil.com",
+            "https://example.org",
+            "https://evilexample.org",
+            "http://chat.example.org",
+            "null",
+        ];
+        for origin in forbidden.iter() {
+            assert!(!policy.is_allowed(Some(origin)), "{}", origin);
+        }
+        let policy = OriginPolicy::predicate(|origin| origin == Some("https://example.com"));
+        assert!(policy.is_allowed(Some("https://example.com")));
+        assert!(!policy.is_allowed(None));
+        assert!(OriginPolicy::default().is_allowed(Some("https://evil.com")));
+        let request = handshake_request("", None);
+        assert_eq!(
+            request.validate_origin(&OriginPolicy::allow_list(&["https://example.com"])),
+            Ok(())
+        );
+    }
+    #[test]
+    fn test_uri() {
+        let uri = Uri::new("/rooms/my%20room/?token=a%2Bb&tag=x&tag=y+z&empty#top");
+        assert_eq!(uri.get_raw_path(), "/rooms/my%20room/");
+        assert_eq!(uri.get_path().unwrap(), "/rooms/my room/");
+        assert_eq!(uri.get_segments().unwrap(), vec!["rooms", "my room"]);
+        let query = uri.get_query().unwrap();
+        assert_eq!(query.get("token"), Some("a+b"));
+        assert_eq!(query.get_all("tag"), vec!["x", "y z"]);
+        assert_eq!(query.get("empty"), Some(""));
+        assert_eq!(query.get("missing"), None);
+        assert_eq!(query.len(), 4);
+        // An encoded slash stays in its segment
+        let uri = Uri::new("/files/a%2Fb");
+        assert_eq!(uri.get_segments().unwrap(), vec!["files", "a/b"]);
+        let uri = Uri::new("http://example.com:8080/chat?room=1");
+        assert_eq!(uri.get_path().unwrap(), "/chat");
+        assert_eq!(uri.get_query().unwrap().get("room"), Some("1"));
+        assert_eq!(Uri::new("/").get_segments().unwrap(), Vec::<String>::new());
+        assert_eq!(Uri::new("/").get_raw_query(), None);
+    }
+    #[test]
+    fn test_invalid_uri() {
+        assert_eq!(
+            Uri::new("/rooms/%4").get_path(),
+            Err(UriError::InvalidPercentEncoding)
+        );
+        assert_eq!(
+            Uri::new("/rooms?name=%zz").get_query(),
+            Err(UriError::InvalidPercentEncoding)
+        );
+        assert_eq!(
+            Uri::new("/rooms/%ff").get_segments(),
+            Err(UriError::InvalidUtf8)
+        );
+    }
+    #[test]
+    fn test_validate_handshake_request_line() {
+        let request = Request::from_str("POST /chat HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(
+            request.validate_handshake(),
+            Err(HandshakeError::InvalidMethod)
+        );
+        let request = Request::from_str("GET /chat HTTP/1.0\r\n\r\n").unwrap();
+        assert_eq!(
+            request.validate_handshake(),
+            Err(HandshakeError::InvalidVersion)
+        );
+        assert_eq!(request.get_endpoint().get_version(), "HTTP/1.0");
+    }
+    #[test]
+    fn test_handshake_error_response() {
+        let response = HandshakeError::UnsupportedVersion
+            .get_response()
+            .to_string();
+        assert!(response.starts_with("HTTP/1.1 426 Upgrade Required\r\n"));
+        assert!(response.contains("\r\nSec-WebSocket-Version: 13\r\n"));
+        let response = HandshakeError::InvalidKey.get_response().to_string();
+        assert_eq!(
+            response,
+            "HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\n\r\n"
+        );
+        let response = Response::new(401, "Unauthorized")
+            .with_header("WWW-Authenticate", "Bearer")
+            .with_body("Who are you?");
+        assert_eq!(response.to_string(), "HTTP/1.1 401 Unauthorized\r\nWWW-Authenticate: Bearer\r\nContent-Length: 12\r\n\r\nWho are you?");
+    }
+    #[test]
+    fn test_header_injection() {
+        let response = Response::new(200, "OK\r\nSet-Cookie: a=b").with_header(
+            "X-Trace\r\nSet-Cookie",
+            "1\r\nSet-Cookie: session=evil\r\n\r\n",
+        );
+        assert_eq!(
+            response.to_string(),
+            "HTTP/1.1 200 OKSet-Cookie: a=b\r\nX-TraceSet-Cookie: 1Set-Cookie: session=evil\r\nContent-Length: 0\r\n\r\n"
+        );
+        let decision = HandshakeDecision::accept(()).with_header("X-User", "5\nSet-Cookie: a=b");
+        let headers = match decision {
+            HandshakeDecision::Accept { mut headers, .. } => {
+                assert_eq!(
+                    headers,
+                    vec![("X-User".to_string(), "5Set-Cookie: a=b".to_string())]
+                );
+                // Headers added by hand are cleaned up when the response is written
+                headers.push(("X-Room".to_string(), "1\r\n\r\nHello".to_string()));
+                headers
+            }
+            HandshakeDecision::Reject(_) => unreachable!(),
+        };
+        let response = get_handshake_response("dGhlIHNhbXBsZSBub25jZQ==", &headers);
+        assert!(response.ends_with("X-User: 5Set-Cookie: a=b\r\nX-Room: 1Hello\r\n\r\n"));
+    }
+    #[test]
+    fn test_request_across_reads() {
+        let bytes = b"POST / HTTP/1.1\r\nHost: localhost\r\nContent-Length: 11\r\n\r\n{ \"id\": 5 }\x81\x00";
+        let mut decoder = RequestDecoder::new();
+        for byte in bytes.iter().take(bytes.len() - 3) {
+            assert!(decoder.next_request().unwrap().is_none());
+            decoder.extend(&[*byte]);
+        }
+        decoder.extend(&bytes[bytes.len() - 3..]);
+        let request = decoder.next_request().unwrap().unwrap();
+        assert_eq!(request.get_endpoint().get_method(), &HTTPMethod::POST);
+        assert_eq!(request.get_body().unwrap().get_body(), "{ \"id\": 5 }");
+        assert_eq!(request.get_remaining(), &[129, 0]);
+    }
+    #[test]
+    fn test_request_without_body() {
+        let mut decoder = RequestDecoder::new();
+        decoder.extend(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        let request = decoder.next_request().unwrap().unwrap();
+        assert_eq!(request.get_body(), None);
+        assert_eq!(request.get_remaining(), &[] as &[u8]);
+    }
+    #[test]
+    fn test_request_too_large() {
+        let mut decoder = RequestDecoder::new().with_max_header_size(32);
+        decoder.extend(b"GET / HTTP/1.1\r\nHost: localhost\r\n");
+        assert_eq!(
+            decoder.next_request().unwrap_err(),
+            WsGonzaleError::HeadersTooLarge
+        );
+        let mut decoder = RequestDecoder::new().with_max_body_size(10);
+        decoder.extend(b"POST / HTTP/1.1\r\nContent-Length: 11\r\n\r\n");
+        assert_eq!(
+            decoder.next_request().unwrap_err(),
+            WsGonzaleError::RequestTooLarge
+        );
+    }
+    #[test]
+    fn test_invalid_request() {
+        let mut decoder = RequestDecoder::new();
+        decoder.extend(b"POST / HTTP/1.1\r\nContent-Length: eleven\r\n\r\n");
+        assert_eq!(
+            decoder.next_request().unwrap_err(),
+            WsGonzaleError::InvalidPayload
+        );
+        let mut decoder = RequestDecoder::new();
+        decoder.extend(b"GET / HTTP/1.1\r\nHost: \xff\r\n\r\n");
+        assert_eq!(
+            decoder.next_request().unwrap_err(),
+            WsGonzaleError::InvalidPayload
+        );
+    }
+    #[test]
+    fn test_headers() {
+        let request = Request::from_str("GET / HTTP/1.1\r\nconnection: keep-alive, Upgrade\r\nX-Forwarded-For:10.0.0.1\r\nx-forwarded-for: 10.0.0.2 \r\nHost: 127.0.0.1:8080\r\n\r\n").unwrap();
+        let headers = request.get_headers();
+        assert_eq!(headers.get("Connection").unwrap(), "keep-alive, Upgrade");
+        assert_eq!(
+            headers.get_tokens("CONNECTION"),
+            vec!["keep-alive", "Upgrade"]
+        );
+        assert!(headers.has_token("Connection", "upgrade"));
+        assert!(!headers.has_token("Connection", "close"));
+        assert_eq!(headers.get_all("X-Forwarded-For"), ["10.0.0.1", "10.0.0.2"]);
+        assert_eq!(headers.get("host").unwrap(), "127.0.0.1:8080");
+        assert_eq!(headers.get_all("Upgrade"), [] as [String; 0]);
+        assert_eq!(headers.len(), 3);
+        let request = Request::from_str("GET / HTTP/1.1\r\nhost: localhost\r\nupgrade: WebSocket\r\nconnection: upgrade\r\nsec-websocket-key: dGhlIHNhbXBsZSBub25jZQ==\r\nsec-websocket-version: 13\r\n\r\n").unwrap();
+        assert!(request.validate_handshake().is_ok());
+    }
+    #[test]
     fn test_small_post() {
         let request = r#"POST / HTTP/1.1
         Content-Type: application/javascript
@@ -258,8 +1382,28 @@ mod tests {
             id: 5
         }"#;
         let result = Request::from_str(request).unwrap();
-        assert_eq!(result.headers.0.len(), 9);
+        assert_eq!(result.headers.len(), 9);
         assert_eq!(result.endpoint.method, HTTPMethod::GET);
         assert_eq!(result.body, None);
     }
+    #[test]
+    fn test_http_methods() {
+        for method in [
+            "GET", "HEAD", "POST", "PUT", "PATCH", "DELETE", "OPTIONS", "CONNECT", "TRACE",
+        ]
+        .iter()
+        {
+            let endpoint = Endpoint::new(&format!("{} / HTTP/1.1", method));
+            assert_eq!(endpoint.get_method().as_str(), Some(*method));
+        }
+        assert_eq!(
+            Endpoint::new("PROPFIND / HTTP/1.1").get_method(),
+            &HTTPMethod::Unknown
+        );
+        assert_eq!(HTTPMethod::Unknown.as_str(), None);
+        let request =
+            Request::from_str("DELETE /rooms/42 HTTP/1.1\r\nContent-Length: 2\r\n\r\nok").unwrap();
+        assert_eq!(request.get_endpoint().get_method(), &HTTPMethod::DELETE);
+        assert_eq!(request.get_body().unwrap().get_body(), "ok");
+    }
 }
@@ -1,14 +1,28 @@
+pub mod config;
 pub mod connection;
 pub mod dataframe;
+pub mod decoder;
+pub mod deflate;
+pub mod extension;
 pub mod handshake;
 pub mod message;
+pub mod protocol;
+pub mod router;
 pub mod server;
+pub mod stream;
 
+pub use self::config::*;
 pub use self::connection::*;
 pub use self::dataframe::*;
+pub use self::decoder::*;
+pub use self::deflate::*;
+pub use self::extension::*;
 pub use self::handshake::*;
 pub use self::message::*;
+pub use self::protocol::*;
+pub use self::router::*;
 pub use self::server::*;
+pub use self::stream::*;
 
 pub use async_channel;
 pub use async_net;
@@ -22,12 +36,79 @@ pub type Channel<T> = (Sender<T>, Receiver<T>);
 pub type AsyncResult<T> = Result<T, std::io::Error>;
 pub type WsGonzaleResult<T> = Result<T, WsGonzaleError>;
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum WsGonzaleError {
     InvalidPayload,
     ConnectionClosed,
+    /// A client frame without a masking key
+    UnmaskedFrame,
+    /// RSV1, RSV2 or RSV3 is set but no extension was negotiated for it
+    ReservedBitsSet,
+    /// Close, ping and pong frames can't have a payload over 125 bytes
+    ControlFrameTooLarge,
+    /// Close, ping and pong frames can't be fragmented
+    FragmentedControlFrame,
+    /// One of the reserved opcodes 3-7 or 11-15
+    UnknownOpcode,
+    /// A continuation frame without a fragmented message to continue
+    UnexpectedContinuation,
+    /// A new message started before the last fragmented message was finished
+    ExpectedContinuation,
+    /// A text message or the reason of a close frame isn't valid UTF-8
+    InvalidUtf8,
+    /// A frame payload over [`WsConfig::max_frame_size`](`crate::config::WsConfig::max_frame_size`)
+    FrameTooLarge,
+    /// A message payload over [`WsConfig::max_message_size`](`crate::config::WsConfig::max_message_size`)
+    MessageTooLarge,
+    /// A close frame with a status code that is cut short or isn't allowed on the wire
+    InvalidCloseCode,
+    /// A compressed message that couldn't be inflated
+    InvalidCompression,
+    /// An HTTP request with a request line and headers over [`WsConfig::max_header_size`](`crate::config::WsConfig::max_header_size`)
+    HeadersTooLarge,
+    /// An HTTP request with a body over [`WsConfig::max_body_size`](`crate::config::WsConfig::max_body_size`)
+    RequestTooLarge,
+    /// A request that can't be upgraded, it was answered with [`HandshakeError::get_response`]
+    InvalidHandshake(HandshakeError),
+    /// The handshake callback rejected the request, it was answered with a response with this status
+    HandshakeRejected(u16),
+    /// A request path that can't be decoded, it was answered with `400 Bad Request`
+    InvalidUri(UriError),
     Unknown,
 }
+impl WsGonzaleError {
+    /// The status code we close the connection with when we hit this error, `None` if it isn't a protocol error
+    pub fn get_close_code(&self) -> Option<CloseCode> {
+        match self {
+            WsGonzaleError::UnmaskedFrame
+            | WsGonzaleError::ReservedBitsSet
+            | WsGonzaleError::ControlFrameTooLarge
+            | WsGonzaleError::FragmentedControlFrame
+            | WsGonzaleError::UnknownOpcode
+            | WsGonzaleError::UnexpectedContinuation
+            | WsGonzaleError::ExpectedContinuation
+            | WsGonzaleError::InvalidCloseCode => Some(CloseCode::ProtocolError),
+            WsGonzaleError::InvalidUtf8 | WsGonzaleError::InvalidCompression => {
+                Some(CloseCode::InvalidPayload)
+            }
+            WsGonzaleError::FrameTooLarge | WsGonzaleError::MessageTooLarge => {
+                Some(CloseCode::MessageTooBig)
+            }
+            _ => None,
+        }
+    }
+    /// The HTTP response we answer a request we couldn't read with, `None` if there's nothing to answer
+    pub fn get_response(&self) -> Option<Response> {
+        match self {
+            WsGonzaleError::HeadersTooLarge => {
+                Some(Response::new(431, "Request Header Fields Too Large"))
+            }
+            WsGonzaleError::RequestTooLarge => Some(Response::new(413, "Content Too Large")),
+            WsGonzaleError::InvalidPayload => Some(Response::new(400, "Bad Request")),
+            _ => None,
+        }
+    }
+}
 impl From<std::io::Error> for WsGonzaleError {
     fn from(error: std::io::Error) -> Self {
         match error.kind() {
@@ -46,8 +127,8 @@ impl From<WsGonzaleError> for std::io::Error {
     fn from(error: WsGonzaleError) -> Self {
         let error_kind = match error {
             WsGonzaleError::ConnectionClosed => std::io::ErrorKind::ConnectionAborted,
-            WsGonzaleError::InvalidPayload => std::io::ErrorKind::InvalidData,
-            _ => std::io::ErrorKind::Other,
+            WsGonzaleError::Unknown => std::io::ErrorKind::Other,
+            _ => std::io::ErrorKind::InvalidData,
         };
         std::io::Error::from(error_kind)
     }
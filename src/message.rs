@@ -1,10 +1,180 @@
+use crate::{WsGonzaleError, WsGonzaleResult};
+
 /// Represents the Message type derived from an opcode in a [Dataframe](`crate::dataframe::Dataframe`) payload
 #[derive(Clone, Debug, PartialEq)]
 pub enum Message {
     Binary(Vec<u8>),
     Text(String),
-    Ping(String),
-    Pong(String),
-    Close,
+    Ping(Vec<u8>),
+    Pong(Vec<u8>),
+    Close(Option<CloseFrame>),
     Unknown,
 }
+/// Status code of a close frame, see RFC 6455 section 7.4
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CloseCode {
+    /// 1000, the purpose of the connection has been fulfilled
+    Normal,
+    /// 1001, the client navigated away or the server is going down
+    GoingAway,
+    /// 1002, the endpoint received a frame breaking the protocol
+    ProtocolError,
+    /// 1003, the endpoint received a type of data it can't accept
+    Unsupported,
+    /// 1005, reserved for when a close frame didn't contain a status code, never sent on the wire
+    NoStatusReceived,
+    /// 1006, reserved for when the connection dropped without a close frame, never sent on the wire
+    Abnormal,
+    /// 1007, the data in a message wasn't consistent with its type, e.g. invalid UTF-8 in a text message
+    InvalidPayload,
+    /// 1008, a generic code for when a message violated the endpoint's policy
+    PolicyViolation,
+    /// 1009, a message was too big to process
+    MessageTooBig,
+    /// 1010, the client expected the server to negotiate an extension
+    MandatoryExtension,
+    /// 1011, the server hit an unexpected condition
+    InternalError,
+    /// 1012, the server is restarting
+    ServiceRestart,
+    /// 1013, the server is overloaded, try again later
+    TryAgainLater,
+    /// 1014, the server acted as a gateway and got an invalid response
+    BadGateway,
+    /// 1015, reserved for a failed TLS handshake, never sent on the wire
+    TlsHandshake,
+    /// 3000-3999, registered with IANA for libraries and frameworks
+    Registered(u16),
+    /// 4000-4999, private use by applications
+    Application(u16),
+    /// Any code not covered by RFC 6455
+    Unknown(u16),
+}
+impl From<u16> for CloseCode {
+    fn from(code: u16) -> CloseCode {
+        match code {
+            1000 => CloseCode::Normal,
+            1001 => CloseCode::GoingAway,
+            1002 => CloseCode::ProtocolError,
+            1003 => CloseCode::Unsupported,
+            1005 => CloseCode::NoStatusReceived,
+            1006 => CloseCode::Abnormal,
+            1007 => CloseCode::InvalidPayload,
+            1008 => CloseCode::PolicyViolation,
+            1009 => CloseCode::MessageTooBig,
+            1010 => CloseCode::MandatoryExtension,
+            1011 => CloseCode::InternalError,
+            1012 => CloseCode::ServiceRestart,
+            1013 => CloseCode::TryAgainLater,
+            1014 => CloseCode::BadGateway,
+            1015 => CloseCode::TlsHandshake,
+            3000..=3999 => CloseCode::Registered(code),
+            4000..=4999 => CloseCode::Application(code),
+            _ => CloseCode::Unknown(code),
+        }
+    }
+}
+impl From<CloseCode> for u16 {
+    fn from(code: CloseCode) -> u16 {
+        match code {
+            CloseCode::Normal => 1000,
+            CloseCode::GoingAway => 1001,
+            CloseCode::ProtocolError => 1002,
+            CloseCode::Unsupported => 1003,
+            CloseCode::NoStatusReceived => 1005,
+            CloseCode::Abnormal => 1006,
+            CloseCode::InvalidPayload => 1007,
+            CloseCode::PolicyViolation => 1008,
+            CloseCode::MessageTooBig => 1009,
+            CloseCode::MandatoryExtension => 1010,
+            CloseCode::InternalError => 1011,
+            CloseCode::ServiceRestart => 1012,
+            CloseCode::TryAgainLater => 1013,
+            CloseCode::BadGateway => 1014,
+            CloseCode::TlsHandshake => 1015,
+            CloseCode::Registered(code)
+            | CloseCode::Application(code)
+            | CloseCode::Unknown(code) => code,
+        }
+    }
+}
+impl CloseCode {
+    /// Whether the code is allowed to be sent in a close frame
+    pub fn is_allowed(&self) -> bool {
+        !matches!(
+            self,
+            CloseCode::NoStatusReceived
+                | CloseCode::Abnormal
+                | CloseCode::TlsHandshake
+                | CloseCode::Unknown(_)
+        )
+    }
+}
+/// Status code and reason sent in a close frame
+#[derive(Clone, Debug, PartialEq)]
+pub struct CloseFrame {
+    pub code: CloseCode,
+    pub reason: String,
+}
+impl CloseFrame {
+    pub fn new(code: CloseCode, reason: &str) -> CloseFrame {
+        CloseFrame {
+            code,
+            reason: reason.to_string(),
+        }
+    }
+    /// Parses the payload of a close frame, an empty payload means there was no status code
+    pub fn from_payload(payload: &[u8]) -> WsGonzaleResult<Option<CloseFrame>> {
+        match payload {
+            [] => Ok(None),
+            [first, second, reason @ ..] => Ok(Some(CloseFrame {
+                code: CloseCode::from(u16::from_be_bytes([*first, *second])),
+                reason: String::from_utf8(reason.to_vec())
+                    .map_err(|_| WsGonzaleError::InvalidUtf8)?,
+            })),
+            // A status code is always two bytes
+            _ => Err(WsGonzaleError::InvalidCloseCode),
+        }
+    }
+    /// The payload of a close frame, two bytes of status code followed by the reason
+    pub fn get_payload(&self) -> Vec<u8> {
+        let mut payload = u16::from(self.code).to_be_bytes().to_vec();
+        payload.extend_from_slice(self.reason.as_bytes());
+        payload
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_close_code_round_trip() {
+        for code in 0..5000u16 {
+            assert_eq!(u16::from(CloseCode::from(code)), code);
+        }
+        assert_eq!(CloseCode::from(4000), CloseCode::Application(4000));
+        assert_eq!(CloseCode::from(3000), CloseCode::Registered(3000));
+    }
+    #[test]
+    fn test_close_frame_payload() {
+        let close_frame = CloseFrame::new(CloseCode::Normal, "Bye");
+        let payload = close_frame.get_payload();
+        assert_eq!(payload, vec![3, 232, 66, 121, 101]);
+        assert_eq!(CloseFrame::from_payload(&payload), Ok(Some(close_frame)));
+    }
+    #[test]
+    fn test_close_frame_invalid_reason() {
+        assert_eq!(
+            CloseFrame::from_payload(&[3, 232, 255]),
+            Err(WsGonzaleError::InvalidUtf8)
+        );
+    }
+    #[test]
+    fn test_close_frame_without_status_code() {
+        assert_eq!(CloseFrame::from_payload(&[]), Ok(None));
+        assert_eq!(
+            CloseFrame::from_payload(&[3]),
+            Err(WsGonzaleError::InvalidCloseCode)
+        );
+    }
+}
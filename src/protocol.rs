@@ -0,0 +1,464 @@
+use crate::{
+    config::{WsConfig, DEFAULT_MAX_MESSAGE_SIZE},
+    dataframe::{self, Opcode},
+    decoder::FrameDecoder,
+    extension::{Extensions, Frame},
+    message::{CloseCode, CloseFrame, Message},
+    WsGonzaleError, WsGonzaleResult,
+};
+
+/// Validates UTF-8 that arrives in chunks without keeping more than an unfinished character around,
+/// used for both buffered and streamed text messages so they fail on the same fragment
+#[derive(Clone, Default)]
+pub(crate) struct Utf8Validator {
+    /// The start of a multi-byte character cut off at the end of the last chunk
+    incomplete: Vec<u8>,
+}
+impl Utf8Validator {
+    pub(crate) fn push(&mut self, mut chunk: &[u8]) -> WsGonzaleResult<()> {
+        // Finish the character from the last chunk first
+        while !self.incomplete.is_empty() {
+            let (first, rest) = match chunk.split_first() {
+                Some(split) => split,
+                None => return Ok(()),
+            };
+            self.incomplete.push(*first);
+            chunk = rest;
+            match std::str::from_utf8(&self.incomplete) {
+                Ok(_) => self.incomplete.clear(),
+                Err(error) if error.error_len().is_none() => {}
+                Err(_) => return Err(WsGonzaleError::InvalidUtf8),
+            }
+        }
+        match std::str::from_utf8(chunk) {
+            Ok(_) => Ok(()),
+            Err(error) if error.error_len().is_none() => {
+                self.incomplete = chunk[error.valid_up_to()..].to_vec();
+                Ok(())
+            }
+            Err(_) => Err(WsGonzaleError::InvalidUtf8),
+        }
+    }
+    /// Fails if the last character was cut short
+    pub(crate) fn finish(&self) -> WsGonzaleResult<()> {
+        if self.incomplete.is_empty() {
+            Ok(())
+        } else {
+            Err(WsGonzaleError::InvalidUtf8)
+        }
+    }
+}
+/// Buffers the payloads of a fragmented message until the final (FIN) frame has arrived
+#[derive(Clone)]
+struct Fragments {
+    /// Opcode of the first frame in the message, `None` when we aren't in the middle of a fragmented message
+    opcode: Option<u8>,
+    payload: Vec<u8>,
+    /// Validates a text payload as the fragments arrive, so we can fail on the fragment with the invalid bytes
+    utf8_validator: Utf8Validator,
+    max_message_size: usize,
+}
+impl Default for Fragments {
+    fn default() -> Self {
+        Fragments::new(DEFAULT_MAX_MESSAGE_SIZE)
+    }
+}
+impl Fragments {
+    fn new(max_message_size: usize) -> Fragments {
+        Fragments {
+            opcode: None,
+            payload: Vec::new(),
+            utf8_validator: Utf8Validator::default(),
+            max_message_size,
+        }
+    }
+    /// Pushes a [`Frame`] and returns a [`Message`] once it's complete.
+    /// Control frames are allowed in between fragments so they are returned right away without touching the buffer.
+    fn push(&mut self, frame: Frame) -> WsGonzaleResult<Option<Message>> {
+        let Frame {
+            fin: is_fin,
+            opcode,
+            payload,
+            ..
+        } = frame;
+        if Opcode::is_control(opcode) {
+            return dataframe::get_message_from_payload(opcode, payload).map(Some);
+        }
+        // Checked before the payload is appended to what we already have
+        let message_size = self.payload.len() as u64 + payload.len() as u64;
+        if message_size > self.max_message_size as u64 {
+            return Err(WsGonzaleError::MessageTooLarge);
+        }
+        match (self.opcode, Opcode::from(opcode)) {
+            // A continuation frame without a started message
+            (None, Opcode::Continuation) => Err(WsGonzaleError::UnexpectedContinuation),
+            // Unfragmented message, no need to buffer anything
+            (None, _) if is_fin => dataframe::get_message_from_payload(opcode, payload).map(Some),
+            (None, _) => {
+                self.utf8_validator = Utf8Validator::default();
+                self.validate_utf8(opcode, &payload, false)?;
+                self.opcode = Some(opcode);
+                self.payload = payload;
+                Ok(None)
+            }
+            (Some(first_opcode), Opcode::Continuation) => {
+                self.validate_utf8(first_opcode, &payload, is_fin)?;
+                self.payload.extend_from_slice(&payload);
+                if !is_fin {
+                    return Ok(None);
+                }
+                self.opcode = None;
+                let payload = std::mem::take(&mut self.payload);
+                dataframe::get_message_from_payload(first_opcode, payload).map(Some)
+            }
+            // A new message started before the last one finished
+            (Some(_), _) => Err(WsGonzaleError::ExpectedContinuation),
+        }
+    }
+    /// Validates the UTF-8 of a fragment of a text message.
+    /// A multi-byte character cut off at the end of a fragment is fine unless it's the final one.
+    fn validate_utf8(&mut self, opcode: u8, payload: &[u8], is_fin: bool) -> WsGonzaleResult<()> {
+        if Opcode::from(opcode) != Opcode::Text {
+            return Ok(());
+        }
+        self.utf8_validator.push(payload)?;
+        if is_fin {
+            self.utf8_validator.finish()?;
+        }
+        Ok(())
+    }
+}
+
+/// The WebSocket protocol of one connection without any IO, it doesn't know where the bytes come from or go to.
+/// Feed it what was read with [`WsProtocol::receive`], take the messages with [`WsProtocol::next_message`]
+/// and write what [`WsProtocol::take_outgoing`] returns. Pings are answered, a close frame is echoed
+/// and protocol errors close the connection with the matching [`CloseCode`] on their own.
+pub struct WsProtocol {
+    decoder: FrameDecoder,
+    fragments: Fragments,
+    config: WsConfig,
+    /// Decode the frames we receive and encode the ones we send, in the order they are written
+    extensions: Extensions,
+    /// RSV bits claimed by the negotiated extensions
+    allowed_rsv: u8,
+    /// Frames waiting to be written, they are run through the extensions when taken
+    outgoing: Vec<Vec<u8>>,
+    /// The close frame the client sent or the one we closed the connection with,
+    /// [`CloseCode::Abnormal`] until then
+    close_frame: CloseFrame,
+    is_close_sent: bool,
+    is_close_received: bool,
+}
+impl WsProtocol {
+    pub fn new(config: WsConfig) -> WsProtocol {
+        WsProtocol::with_extensions(config, Extensions::default())
+    }
+    /// Same as [`WsProtocol::new`] with the [`Extensions`] negotiated in the handshake
+    pub fn with_extensions(config: WsConfig, extensions: Extensions) -> WsProtocol {
+        WsProtocol {
+            decoder: FrameDecoder::new().with_max_frame_size(config.get_max_frame_size()),
+            fragments: Fragments::new(config.get_max_message_size()),
+            config,
+            allowed_rsv: extensions.get_rsv(),
+            extensions,
+            outgoing: Vec::new(),
+            close_frame: CloseFrame::new(CloseCode::Abnormal, ""),
+            is_close_sent: false,
+            is_close_received: false,
+        }
+    }
+    pub fn get_config(&self) -> &WsConfig {
+        &self.config
+    }
+    pub fn get_close_frame(&self) -> &CloseFrame {
+        &self.close_frame
+    }
+    /// We sent a close frame, nothing else can be sent
+    pub fn is_closing(&self) -> bool {
+        self.is_close_sent
+    }
+    /// Both sides sent a close frame, the tcp_stream can be shut down
+    pub fn is_closed(&self) -> bool {
+        self.is_close_sent && self.is_close_received
+    }
+    /// Whether we are in the middle of a fragmented message
+    pub fn is_fragmented(&self) -> bool {
+        self.fragments.opcode.is_some()
+    }
+    /// Appends bytes read from the client, they don't have to line up with the frames
+    pub fn receive(&mut self, bytes: &[u8]) {
+        self.decoder.extend(bytes);
+    }
+    /// Returns the next complete [`Message`], `None` means we need more bytes.
+    /// Control frames in between the fragments of a message are returned as they come.
+    pub fn next_message(&mut self) -> WsGonzaleResult<Option<Message>> {
+        while let Some(frame) = self.next_frame()? {
+            if let Some(message) = self.push_frame(frame)? {
+                return Ok(Some(message));
+            }
+        }
+        Ok(None)
+    }
+    /// Returns the next frame validated and run through the extensions, `None` means we need more bytes
+    pub fn next_frame(&mut self) -> WsGonzaleResult<Option<Frame>> {
+        let frame = self.decode_frame();
+        self.fail_on_error(frame)
+    }
+    /// Buffers the frame of a fragmented message and returns the [`Message`] once it's complete
+    pub fn push_frame(&mut self, frame: Frame) -> WsGonzaleResult<Option<Message>> {
+        let message = self
+            .fragments
+            .push(frame)
+            .and_then(|message| match message {
+                Some(Message::Close(Some(close_frame)))
+                    if self.config.is_strict() && !close_frame.code.is_allowed() =>
+                {
+                    Err(WsGonzaleError::InvalidCloseCode)
+                }
+                message => Ok(message),
+            });
+        let message = self.fail_on_error(message)?;
+        if let Some(message) = &message {
+            self.answer(message);
+        }
+        Ok(message)
+    }
+    /// Queues a message to be written, fails once we sent a close frame.
+    /// A close frame starting the closing handshake is the one reported until the client answers it.
+    pub fn send(&mut self, message: Message) -> WsGonzaleResult<()> {
+        if let Message::Close(Some(close_frame)) = &message {
+            if !self.is_close_sent && !self.is_close_received {
+                self.close_frame = close_frame.clone();
+            }
+        }
+        self.send_buffer(dataframe::get_buffer(message))
+    }
+    /// Same as [`WsProtocol::send`] with a frame made by [`dataframe::get_buffer`], e.g. one a hook sent through its
+    /// [`Channels`](`crate::connection::Channels`). It's run through the extensions in [`WsProtocol::take_outgoing`].
+    pub fn send_buffer(&mut self, buffer: Vec<u8>) -> WsGonzaleResult<()> {
+        if self.is_close_sent {
+            return Err(WsGonzaleError::ConnectionClosed);
+        }
+        if dataframe::is_close_buffer(&buffer) {
+            self.is_close_sent = true;
+        }
+        self.outgoing.push(buffer);
+        Ok(())
+    }
+    /// Closes the connection with the status code of a protocol error, other errors are ignored
+    pub fn fail(&mut self, error: WsGonzaleError) {
+        let code = match error.get_close_code() {
+            Some(code) => code,
+            None => return,
+        };
+        let _ = self.send(Message::Close(Some(CloseFrame::new(code, ""))));
+    }
+    /// The encoded frames to write to the client, in the order they were queued
+    pub fn take_outgoing(&mut self) -> WsGonzaleResult<Vec<u8>> {
+        let mut buffer = Vec::new();
+        for frame in self.outgoing.drain(..) {
+            buffer.extend_from_slice(&self.extensions.encode_buffer(frame)?);
+        }
+        Ok(buffer)
+    }
+    fn decode_frame(&mut self) -> WsGonzaleResult<Option<Frame>> {
+        // Nothing is allowed to come after a close frame
+        if self.is_close_received {
+            return Err(WsGonzaleError::ConnectionClosed);
+        }
+        let dataframe = match self.decoder.next_frame()? {
+            Some(dataframe) => dataframe,
+            None => return Ok(None),
+        };
+        if self.config.is_strict() {
+            dataframe.validate_with_rsv(self.allowed_rsv)?;
+        }
+        self.extensions.decode(Frame::from(dataframe)).map(Some)
+    }
+    fn fail_on_error<T>(&mut self, result: WsGonzaleResult<T>) -> WsGonzaleResult<T> {
+        result.inspect_err(|error| self.fail(*error))
+    }
+    /// Answers a ping with a pong and echoes a close frame unless we started the closing handshake
+    fn answer(&mut self, message: &Message) {
+        match message {
+            Message::Ping(payload) => {
+                let _ = self.send(Message::Pong(payload.clone()));
+            }
+            Message::Close(close_frame) => {
+                self.is_close_received = true;
+                self.close_frame = close_frame
+                    .clone()
+                    .unwrap_or_else(|| CloseFrame::new(CloseCode::NoStatusReceived, ""));
+                let _ = self.send(message.clone());
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dataframe::DataframeBuilder;
+    /// Builds a masked dataframe with a masking key of [0, 0, 0, 0] so the payload is readable
+    fn dataframe(first_byte: u8, payload: &[u8]) -> Frame {
+        let mut buffer: Vec<u8> = vec![first_byte, 128 + payload.len() as u8, 0, 0, 0, 0];
+        buffer.extend_from_slice(payload);
+        Frame::from(DataframeBuilder::new(buffer).unwrap())
+    }
+    #[test]
+    fn test_unfragmented_message() {
+        let mut fragments = Fragments::default();
+        let message = fragments.push(dataframe(129, b"Hello")).unwrap();
+        assert_eq!(message, Some(Message::Text("Hello".to_string())));
+    }
+    #[test]
+    fn test_fragmented_message() {
+        let mut fragments = Fragments::default();
+        // Text(1) without FIN
+        assert_eq!(fragments.push(dataframe(1, b"Hel")).unwrap(), None);
+        // Continuation(0) without FIN
+        assert_eq!(fragments.push(dataframe(0, b"lo ")).unwrap(), None);
+        // FIN(128) + Continuation(0)
+        let message = fragments.push(dataframe(128, b"World")).unwrap();
+        assert_eq!(message, Some(Message::Text("Hello World".to_string())));
+    }
+    #[test]
+    fn test_control_frame_between_fragments() {
+        let mut fragments = Fragments::default();
+        assert_eq!(fragments.push(dataframe(1, b"Hel")).unwrap(), None);
+        // FIN(128) + Ping(9) is returned right away
+        assert!(fragments.push(dataframe(137, b"")).unwrap().is_some());
+        let message = fragments.push(dataframe(128, b"lo")).unwrap();
+        assert_eq!(message, Some(Message::Text("Hello".to_string())));
+    }
+    #[test]
+    fn test_character_split_across_fragments() {
+        let mut fragments = Fragments::default();
+        // "€" is [226, 130, 172]
+        assert_eq!(fragments.push(dataframe(1, &[72, 226])).unwrap(), None);
+        assert_eq!(fragments.push(dataframe(0, &[130])).unwrap(), None);
+        let message = fragments.push(dataframe(128, &[172])).unwrap();
+        assert_eq!(message, Some(Message::Text("H€".to_string())));
+    }
+    #[test]
+    fn test_invalid_utf8_in_first_fragment() {
+        let mut fragments = Fragments::default();
+        let result = fragments.push(dataframe(1, &[72, 255]));
+        assert_eq!(result.err().unwrap(), WsGonzaleError::InvalidUtf8);
+    }
+    #[test]
+    fn test_character_cut_short_at_last_fragment() {
+        let mut fragments = Fragments::default();
+        assert_eq!(fragments.push(dataframe(1, &[72, 226])).unwrap(), None);
+        let result = fragments.push(dataframe(128, &[130]));
+        assert_eq!(result.err().unwrap(), WsGonzaleError::InvalidUtf8);
+    }
+    #[test]
+    fn test_utf8_validator() {
+        let mut validator = Utf8Validator::default();
+        // "€" is [226, 130, 172]
+        validator.push(&[72, 226]).unwrap();
+        validator.push(&[130]).unwrap();
+        assert_eq!(validator.finish(), Err(WsGonzaleError::InvalidUtf8));
+        validator.push(&[172, 72]).unwrap();
+        assert_eq!(validator.finish(), Ok(()));
+        assert_eq!(validator.push(&[226, 72]), Err(WsGonzaleError::InvalidUtf8));
+    }
+    #[test]
+    fn test_message_too_large() {
+        let mut fragments = Fragments::new(5);
+        assert_eq!(fragments.push(dataframe(1, b"Hel")).unwrap(), None);
+        let result = fragments.push(dataframe(128, b"lo!"));
+        assert_eq!(result.err().unwrap(), WsGonzaleError::MessageTooLarge);
+    }
+    #[test]
+    fn test_continuation_without_start() {
+        let mut fragments = Fragments::default();
+        let result = fragments.push(dataframe(128, b"Hello"));
+        assert_eq!(
+            result.err().unwrap(),
+            WsGonzaleError::UnexpectedContinuation
+        );
+    }
+    #[test]
+    fn test_new_message_before_last_fragment() {
+        let mut fragments = Fragments::default();
+        assert_eq!(fragments.push(dataframe(1, b"Hel")).unwrap(), None);
+        let result = fragments.push(dataframe(129, b"Hello"));
+        assert_eq!(result.err().unwrap(), WsGonzaleError::ExpectedContinuation);
+    }
+    /// A masked frame with a masking key of [0, 0, 0, 0] as the client sends it
+    fn masked(first_byte: u8, payload: &[u8]) -> Vec<u8> {
+        let mut buffer: Vec<u8> = vec![first_byte, 128 + payload.len() as u8, 0, 0, 0, 0];
+        buffer.extend_from_slice(payload);
+        buffer
+    }
+    #[test]
+    fn test_message_across_reads() {
+        let mut protocol = WsProtocol::new(WsConfig::default());
+        for byte in masked(129, b"Hello") {
+            assert_eq!(protocol.next_message().unwrap(), None);
+            protocol.receive(&[byte]);
+        }
+        let message = protocol.next_message().unwrap();
+        assert_eq!(message, Some(Message::Text("Hello".to_string())));
+    }
+    #[test]
+    fn test_ping_is_answered() {
+        let mut protocol = WsProtocol::new(WsConfig::default());
+        // FIN(128) + Ping(9)
+        protocol.receive(&masked(137, b"Hi"));
+        let message = protocol.next_message().unwrap();
+        assert_eq!(message, Some(Message::Ping(b"Hi".to_vec())));
+        let pong = dataframe::get_buffer(Message::Pong(b"Hi".to_vec()));
+        assert_eq!(protocol.take_outgoing().unwrap(), pong);
+        assert_eq!(protocol.take_outgoing().unwrap(), Vec::<u8>::new());
+    }
+    #[test]
+    fn test_close_is_echoed() {
+        let mut protocol = WsProtocol::new(WsConfig::default());
+        let close = Message::Close(Some(CloseFrame::new(CloseCode::Normal, "Bye")));
+        // FIN(128) + Close(8), 1000 and "Bye"
+        protocol.receive(&masked(136, &[3, 232, 66, 121, 101]));
+        assert_eq!(protocol.next_message().unwrap(), Some(close.clone()));
+        assert!(protocol.is_closed());
+        assert_eq!(protocol.get_close_frame().code, CloseCode::Normal);
+        assert_eq!(
+            protocol.take_outgoing().unwrap(),
+            dataframe::get_buffer(close)
+        );
+        assert_eq!(
+            protocol.send(Message::Text("Hello".to_string())),
+            Err(WsGonzaleError::ConnectionClosed)
+        );
+    }
+    #[test]
+    fn test_close_we_started() {
+        let mut protocol = WsProtocol::new(WsConfig::default());
+        let close_frame = CloseFrame::new(CloseCode::GoingAway, "Missed pongs");
+        protocol.send(Message::Close(Some(close_frame))).unwrap();
+        assert!(protocol.is_closing());
+        assert_eq!(protocol.get_close_frame().code, CloseCode::GoingAway);
+        protocol.take_outgoing().unwrap();
+        protocol.receive(&masked(136, &[3, 232]));
+        protocol.next_message().unwrap();
+        assert!(protocol.is_closed());
+        // The close isn't echoed
+        assert_eq!(protocol.take_outgoing().unwrap(), Vec::<u8>::new());
+    }
+    #[test]
+    fn test_protocol_error_closes() {
+        let mut protocol = WsProtocol::new(WsConfig::default());
+        // An unmasked text frame
+        protocol.receive(&[129, 2, 72, 105]);
+        assert_eq!(protocol.next_message(), Err(WsGonzaleError::UnmaskedFrame));
+        assert!(protocol.is_closing());
+        assert_eq!(protocol.get_close_frame().code, CloseCode::ProtocolError);
+        let close = Message::Close(Some(CloseFrame::new(CloseCode::ProtocolError, "")));
+        assert_eq!(
+            protocol.take_outgoing().unwrap(),
+            dataframe::get_buffer(close)
+        );
+    }
+}
@@ -0,0 +1,282 @@
+use {
+    crate::{
+        config::WsConfig,
+        connection::{WsClientHook, WsConnection, WsEvents},
+        handshake::{self, HTTPMethod, HandshakeDecision, Request, Response},
+        WsGonzaleError, WsGonzaleResult,
+    },
+    async_net::TcpStream,
+    futures::future::{BoxFuture, Future},
+};
+
+/// Makes the [`WsClientHook`] of a connection upgraded on a WebSocket route or rejects the handshake
+type HookFactory =
+    Box<dyn Fn(&WsUpgrade) -> HandshakeDecision<Box<dyn WsClientHook + Send + Sync>> + Send + Sync>;
+/// Answers a request on an HTTP route
+type HttpHandler = Box<dyn Fn(Request, Params) -> BoxFuture<'static, Response> + Send + Sync>;
+
+/// The parameters captured by a route, e.g. `id` is `42` when `/rooms/:id` matches `/rooms/42`
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Params(Vec<(String, String)>);
+impl Params {
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+/// A WebSocket handshake on a route, the factory of the route decides whether it's upgraded before anything is sent
+pub struct WsUpgrade<'a> {
+    request: &'a Request,
+    params: &'a Params,
+    subprotocol: Option<&'a str>,
+}
+impl<'a> WsUpgrade<'a> {
+    pub fn get_request(&self) -> &'a Request {
+        self.request
+    }
+    pub fn get_params(&self) -> &'a Params {
+        self.params
+    }
+    /// The subprotocol we picked from [`WsConfig::subprotocols`](`crate::config::WsConfig::subprotocols`), `None` if there is none
+    pub fn get_subprotocol(&self) -> Option<&'a str> {
+        self.subprotocol
+    }
+}
+/// A segment of a route's path
+#[derive(Debug, PartialEq)]
+enum Segment {
+    Literal(String),
+    /// `:name` matches any segment
+    Param(String),
+}
+/// A path like `/rooms/:id`, empty segments are left out just like in [`Uri::get_segments`](`crate::handshake::Uri::get_segments`)
+#[derive(Debug, PartialEq)]
+struct Pattern(Vec<Segment>);
+impl Pattern {
+    fn new(path: &str) -> Pattern {
+        let segments = path
+            .split('/')
+            .filter(|segment| !segment.is_empty())
+            .map(|segment| match segment.strip_prefix(':') {
+                Some(name) => Segment::Param(name.to_string()),
+                None => Segment::Literal(segment.to_string()),
+            })
+            .collect();
+        Pattern(segments)
+    }
+    /// The captured parameters if every segment matches
+    fn matches(&self, segments: &[String]) -> Option<Params> {
+        if self.0.len() != segments.len() {
+            return None;
+        }
+        let mut params = Vec::new();
+        for (pattern, segment) in self.0.iter().zip(segments) {
+            match pattern {
+                Segment::Literal(literal) if literal == segment => {}
+                Segment::Literal(_) => return None,
+                Segment::Param(name) => params.push((name.clone(), segment.clone())),
+            }
+        }
+        Some(Params(params))
+    }
+}
+enum Handler {
+    WebSocket(HookFactory),
+    Http(HTTPMethod, HttpHandler),
+}
+struct Route {
+    pattern: Pattern,
+    handler: Handler,
+}
+/// Dispatches the requests on a [`Server`](`crate::server::Server`) by method and path, the first route added that matches wins.
+/// A path that matches only with another method is answered with `405 Method Not Allowed`
+/// and the rest by the fallback, `404 Not Found` by default.
+/// ```no_run
+/// # use ws_gonzale::{HTTPMethod, HandshakeDecision, Response, Router};
+/// # struct ChatHook;
+/// # #[ws_gonzale::async_trait::async_trait]
+/// # impl ws_gonzale::WsClientHook for ChatHook {
+/// #     async fn after_handshake(&mut self) -> Result<(), ()> { Ok(()) }
+/// #     async fn after_drop(&self, _: &ws_gonzale::CloseFrame) -> Result<(), ()> { Ok(()) }
+/// #     async fn on_message(&self, _: &ws_gonzale::Message) -> Result<(), ()> { Ok(()) }
+/// #     fn set_channels(&mut self, _: ws_gonzale::Channels) {}
+/// # }
+/// let router = Router::new()
+///     .websocket("/rooms/:id", |_upgrade| HandshakeDecision::accept(ChatHook))
+///     .route(HTTPMethod::GET, "/metrics", |_request, _params| async {
+///         Response::new(200, "OK").with_body("connections 0")
+///     });
+/// ```
+pub struct Router {
+    routes: Vec<Route>,
+    fallback: HttpHandler,
+}
+impl Default for Router {
+    fn default() -> Self {
+        Self {
+            routes: Vec::new(),
+            fallback: Box::new(|_, _| Box::pin(async { Response::new(404, "Not Found") })),
+        }
+    }
+}
+impl Router {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// WebSocket handshakes on `path` are upgraded and run with the [`WsClientHook`] `factory` accepts them with,
+    /// or answered with the response it rejects them with. Other requests on `path` go on to the next routes.
+    pub fn websocket<H, F>(mut self, path: &str, factory: F) -> Self
+    where
+        H: WsClientHook + Send + Sync + 'static,
+        F: Fn(&WsUpgrade) -> HandshakeDecision<H> + Send + Sync + 'static,
+    {
+        let factory: HookFactory = Box::new(move |upgrade| {
+            factory(upgrade).map(|hook| Box::new(hook) as Box<dyn WsClientHook + Send + Sync>)
+        });
+        self.routes.push(Route {
+            pattern: Pattern::new(path),
+            handler: Handler::WebSocket(factory),
+        });
+        self
+    }
+    /// `method` requests on `path` are answered with the [`Response`] of `handler`, a route for [`HTTPMethod::Unknown`] never matches
+    pub fn route<F, R>(mut self, method: HTTPMethod, path: &str, handler: F) -> Self
+    where
+        F: Fn(Request, Params) -> R + Send + Sync + 'static,
+        R: Future<Output = Response> + Send + 'static,
+    {
+        let handler: HttpHandler =
+            Box::new(move |request, params| Box::pin(handler(request, params)));
+        self.routes.push(Route {
+            pattern: Pattern::new(path),
+            handler: Handler::Http(method, handler),
+        });
+        self
+    }
+    /// Answers the requests no route matched, the [`Params`] are always empty
+    pub fn fallback<F, R>(mut self, handler: F) -> Self
+    where
+        F: Fn(Request, Params) -> R + Send + Sync + 'static,
+        R: Future<Output = Response> + Send + 'static,
+    {
+        self.fallback = Box::new(move |request, params| Box::pin(handler(request, params)));
+        self
+    }
+    /// Reads the request from `tcp_stream` with the size limits of `config` and dispatches it,
+    /// WebSocket connections are upgraded with `config` and run until they close.
+    /// A request that can't be read is answered with [`WsGonzaleError::get_response`], e.g. `431 Request Header Fields Too Large`,
+    /// and a path that can't be decoded with `400 Bad Request`.
+    pub async fn handle(&self, mut tcp_stream: TcpStream, config: WsConfig) -> WsGonzaleResult<()> {
+        let request = Request::read_from_stream_with_config(&mut tcp_stream, &config).await?;
+        let segments = match request.get_endpoint().get_uri().get_segments() {
+            Ok(segments) => segments,
+            Err(error) => {
+                handshake::respond(&Response::new(400, "Bad Request"), &mut tcp_stream).await?;
+                return Err(WsGonzaleError::InvalidUri(error));
+            }
+        };
+        let method = request.get_endpoint().get_method();
+        let is_upgrade =
+            *method == HTTPMethod::GET && request.get_headers().has_token("Upgrade", "websocket");
+        // The methods of the routes matching the path, in case none matches the method
+        let mut allowed_methods = Vec::new();
+        for route in &self.routes {
+            let params = match route.pattern.matches(&segments) {
+                Some(params) => params,
+                None => continue,
+            };
+            match &route.handler {
+                Handler::WebSocket(factory) if is_upgrade => {
+                    let (connection, hook) = WsConnection::upgrade_with_subprotocol_callback(
+                        tcp_stream,
+                        &request,
+                        config,
+                        |request, subprotocol| {
+                            factory(&WsUpgrade {
+                                request,
+                                params: &params,
+                                subprotocol,
+                            })
+                        },
+                    )
+                    .await?;
+                    return WsEvents::with_boxed_hook(connection, hook)
+                        .await?
+                        .run()
+                        .await;
+                }
+                Handler::WebSocket(_) => allowed_methods.push(HTTPMethod::GET),
+                Handler::Http(route_method, handler)
+                    if route_method == method && *method != HTTPMethod::Unknown =>
+                {
+                    let response = handler(request, params).await;
+                    handshake::respond(&response, &mut tcp_stream).await?;
+                    return Ok(());
+                }
+                Handler::Http(HTTPMethod::Unknown, _) => {}
+                Handler::Http(route_method, _) => allowed_methods.push(*route_method),
+            }
+        }
+        let response = if allowed_methods.contains(method) {
+            // Only a WebSocket route matched a GET that isn't a handshake
+            Response::new(426, "Upgrade Required").with_header("Upgrade", "websocket")
+        } else if !allowed_methods.is_empty() {
+            let mut allow: Vec<&str> = Vec::new();
+            for method in allowed_methods.iter().filter_map(HTTPMethod::as_str) {
+                if !allow.contains(&method) {
+                    allow.push(method);
+                }
+            }
+            Response::new(405, "Method Not Allowed").with_header("Allow", &allow.join(", "))
+        } else {
+            (self.fallback)(request, Params::default()).await
+        };
+        handshake::respond(&response, &mut tcp_stream).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    fn segments(path: &str) -> Vec<String> {
+        crate::handshake::Uri::new(path).get_segments().unwrap()
+    }
+    #[test]
+    fn test_pattern() {
+        let pattern = Pattern::new("/rooms/:id/messages/:message");
+        assert_eq!(
+            pattern.0,
+            vec![
+                Segment::Literal("rooms".to_string()),
+                Segment::Param("id".to_string()),
+                Segment::Literal("messages".to_string()),
+                Segment::Param("message".to_string()),
+            ]
+        );
+        let params = pattern.matches(&segments("/rooms/42/messages/7")).unwrap();
+        assert_eq!(params.get("id"), Some("42"));
+        assert_eq!(params.get("message"), Some("7"));
+        assert_eq!(params.get("missing"), None);
+        assert_eq!(pattern.matches(&segments("/rooms/42/messages")), None);
+        assert_eq!(pattern.matches(&segments("/rooms/42/replies/7")), None);
+        // Parameters are decoded
+        let params = Pattern::new("/rooms/:id")
+            .matches(&segments("/rooms/my%20room/"))
+            .unwrap();
+        assert_eq!(params.get("id"), Some("my room"));
+        assert!(Pattern::new("/")
+            .matches(&segments("/"))
+            .unwrap()
+            .is_empty());
+        assert_eq!(Pattern::new("/").matches(&segments("/chat")), None);
+    }
+}
@@ -1,23 +1,59 @@
 use {
-    crate::AsyncResult,
+    crate::{config::WsConfig, router::Router, AsyncResult},
     async_net::{Incoming, TcpListener},
-    async_std::net::SocketAddr,
+    async_std::{net::SocketAddr, sync::Arc, task},
+    futures::StreamExt,
 };
 
 /// A [`TcpListener`] handling incoming [`TcpStream`](`async_net::TcpStream`)
 pub struct Server {
     connection: TcpListener,
+    /// Default [`WsConfig`] for the connections upgraded on this server
+    config: WsConfig,
 }
-impl<'a> Server {
+impl Server {
     /// Opens up a [`TcpListener`] waiting for incoming connections on a given address
     pub async fn new(socket_addr: SocketAddr) -> AsyncResult<Server> {
+        Server::with_config(socket_addr, WsConfig::default()).await
+    }
+    /// Same as [`Server::new`] but with a [`WsConfig`] for the connections on this server
+    pub async fn with_config(socket_addr: SocketAddr, config: WsConfig) -> AsyncResult<Server> {
         let listener = TcpListener::bind(socket_addr).await?;
         Ok(Server {
             connection: listener,
+            config,
         })
     }
     /// Will basically poll-next on an incoming [`TcpStream`](`async_net::TcpStream`)
     pub fn incoming(&self) -> Incoming<'_> {
         self.connection.incoming()
     }
+    /// Pass this to [`WsConnection::upgrade_from_request`](`crate::connection::WsConnection::upgrade_from_request`),
+    /// it can be cloned and changed first for per connection settings.
+    pub fn get_config(&self) -> &WsConfig {
+        &self.config
+    }
+    /// Hands every incoming connection to `router` on its own task with the config of this server.
+    /// A connection that fails to be accepted, e.g. when we're out of file descriptors, is logged and skipped.
+    pub async fn serve(&self, router: Router) -> AsyncResult<()> {
+        let router = Arc::new(router);
+        let mut incoming = self.incoming();
+        while let Some(tcp_stream) = incoming.next().await {
+            let tcp_stream = match tcp_stream {
+                Ok(tcp_stream) => tcp_stream,
+                Err(error) => {
+                    log::warn!("Failed to accept a connection: {}", error);
+                    continue;
+                }
+            };
+            let router = Arc::clone(&router);
+            let config = self.config.clone();
+            task::spawn(async move {
+                if let Err(error) = router.handle(tcp_stream, config).await {
+                    log::debug!("Connection ended with {:?}", error);
+                }
+            });
+        }
+        Ok(())
+    }
 }
@@ -0,0 +1,99 @@
+use {
+    crate::WsGonzaleResult,
+    async_channel::Receiver,
+    futures::{io::AsyncRead, StreamExt},
+    std::{
+        pin::Pin,
+        task::{Context, Poll},
+    },
+};
+
+/// A text or binary message passed to [`WsClientHook::on_message_stream`](`crate::connection::WsClientHook::on_message_stream`)
+/// while its frames are still arriving, read it like any other [`AsyncRead`].
+/// Reading ends with an error instead of EOF if the message is cut short, e.g. by a close frame or invalid UTF-8.
+pub struct MessageStream {
+    is_text: bool,
+    receiver: Receiver<WsGonzaleResult<Vec<u8>>>,
+    /// Payload of the frame we are currently reading from
+    chunk: Vec<u8>,
+    position: usize,
+}
+impl MessageStream {
+    pub(crate) fn new(
+        is_text: bool,
+        receiver: Receiver<WsGonzaleResult<Vec<u8>>>,
+    ) -> MessageStream {
+        MessageStream {
+            is_text,
+            receiver,
+            chunk: Vec::new(),
+            position: 0,
+        }
+    }
+    /// Whether it's a text message, the UTF-8 is validated before the bytes are handed out
+    /// but a character can still be split between two reads
+    pub fn is_text(&self) -> bool {
+        self.is_text
+    }
+}
+impl AsyncRead for MessageStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        loop {
+            if self.position < self.chunk.len() {
+                let length = buf.len().min(self.chunk.len() - self.position);
+                let start = self.position;
+                buf[..length].copy_from_slice(&self.chunk[start..start + length]);
+                self.position += length;
+                return Poll::Ready(Ok(length));
+            }
+            match self.receiver.poll_next_unpin(cx) {
+                Poll::Ready(Some(Ok(chunk))) => {
+                    self.chunk = chunk;
+                    self.position = 0;
+                }
+                Poll::Ready(Some(Err(error))) => return Poll::Ready(Err(error.into())),
+                // The sender is dropped after the final frame
+                Poll::Ready(None) => return Poll::Ready(Ok(0)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use {
+        crate::WsGonzaleError,
+        futures::{executor::block_on, AsyncReadExt},
+    };
+    #[test]
+    fn test_read_across_chunks() {
+        let (sender, receiver) = async_channel::unbounded();
+        sender.try_send(Ok(b"Hel".to_vec())).unwrap();
+        sender.try_send(Ok(Vec::new())).unwrap();
+        sender.try_send(Ok(b"lo".to_vec())).unwrap();
+        drop(sender);
+        let mut stream = MessageStream::new(true, receiver);
+        let mut payload = String::new();
+        block_on(stream.read_to_string(&mut payload)).unwrap();
+        assert_eq!(payload, "Hello");
+    }
+    #[test]
+    fn test_read_error() {
+        let (sender, receiver) = async_channel::unbounded();
+        sender.try_send(Ok(b"Hel".to_vec())).unwrap();
+        sender
+            .try_send(Err(WsGonzaleError::ConnectionClosed))
+            .unwrap();
+        let mut stream = MessageStream::new(false, receiver);
+        let mut payload = Vec::new();
+        let error = block_on(stream.read_to_end(&mut payload)).err().unwrap();
+        assert_eq!(error.kind(), std::io::ErrorKind::ConnectionAborted);
+        assert_eq!(payload, b"Hel");
+    }
+}
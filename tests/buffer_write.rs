@@ -1,11 +1,47 @@
 #[cfg(test)]
 mod tests {
-    use super::*;
-    #[ignore]
+    use ws_gonzale::{
+        DataframeEncoder, FrameDecoder, Message, WsGonzaleError, DEFAULT_MAX_FRAME_SIZE,
+    };
     #[test]
     fn test_super_large_payload() {
-        let _large_payload = std::fs::read_to_string("tests/crashing_payload.txt")
+        let large_payload = std::fs::read_to_string("tests/crashing_payload.txt")
             .expect("Couldn't read crashing payload");
-        todo!("Write a test for this");
+        let buffer = DataframeEncoder::with_masking_key([1, 2, 3, 4])
+            .encode(&Message::Text(large_payload.clone()));
+        // The frame arrives over many reads like it would from a tcp_stream
+        let mut decoder = FrameDecoder::new();
+        let mut chunks = buffer.chunks(8192);
+        let dataframe = loop {
+            if let Some(dataframe) = decoder.next_frame().unwrap() {
+                break dataframe;
+            }
+            decoder.extend(chunks.next().expect("The whole frame was read"));
+        };
+        assert_eq!(
+            dataframe.get_message().unwrap(),
+            Message::Text(large_payload)
+        );
+        assert_eq!(decoder.get_buffered_length(), 0);
+    }
+    #[test]
+    fn test_frame_length_over_max_frame_size() {
+        // FIN(128) + Opcode(1), MASK(128) + PayloadLength(127), a u64 length of 2^63 and a masking key
+        let buffer: Vec<u8> = vec![129, 255, 128, 0, 0, 0, 0, 0, 0, 0, 1, 2, 3, 4];
+        let mut decoder = FrameDecoder::new();
+        decoder.extend(&buffer);
+        assert_eq!(
+            decoder.next_frame().err().unwrap(),
+            WsGonzaleError::FrameTooLarge
+        );
+    }
+    #[test]
+    fn test_payload_at_max_frame_size() {
+        // FIN(128) + Opcode(2), PayloadLength(127) and a u64 length of DEFAULT_MAX_FRAME_SIZE
+        let mut buffer: Vec<u8> = vec![130, 127];
+        buffer.extend_from_slice(&(DEFAULT_MAX_FRAME_SIZE as u64).to_be_bytes());
+        let mut decoder = FrameDecoder::new();
+        decoder.extend(&buffer);
+        assert!(decoder.next_frame().unwrap().is_none());
     }
 }
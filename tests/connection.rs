@@ -0,0 +1,1034 @@
+use {
+    std::time::Duration,
+    ws_gonzale::{
+        async_channel::{self, Receiver, Sender},
+        async_net::{TcpListener, TcpStream},
+        async_std::{future::timeout, task},
+        async_trait::async_trait,
+        futures::{AsyncReadExt, AsyncWriteExt},
+        get_buffer, Channels, CloseCode, CloseFrame, DeflateConfig, Extension, ExtensionOffer,
+        Frame, HTTPMethod, HandshakeDecision, HandshakeError, Message, Opcode, OriginPolicy,
+        Request, Response, Router, WsClientHook, WsConfig, WsConnection, WsEvents, WsGonzaleError,
+        WsGonzaleResult,
+    },
+};
+
+/// Reports the close frame passed to `after_drop` so tests can check it and echoes text and binary messages
+struct Hook(Sender<CloseFrame>, Option<Sender<Vec<u8>>>);
+#[async_trait]
+impl WsClientHook for Hook {
+    async fn after_handshake(&mut self) -> Result<(), ()> {
+        Ok(())
+    }
+    async fn after_drop(&self, close_frame: &CloseFrame) -> Result<(), ()> {
+        let _ = self.0.send(close_frame.clone()).await;
+        Ok(())
+    }
+    async fn on_message(&self, message: &Message) -> Result<(), ()> {
+        if let (Message::Text(_) | Message::Binary(_), Some(sender)) = (message, &self.1) {
+            let _ = sender.send(get_buffer(message.clone())).await;
+        }
+        Ok(())
+    }
+    fn set_channels(&mut self, channels: Channels) {
+        self.1 = Some(channels.0);
+    }
+}
+
+/// Accepts one connection and returns a client that has done the handshake,
+/// e.g. `TestClient::new(config).header("Origin: https://example.com").connect().await`
+struct TestClient {
+    config: WsConfig,
+    extensions: Option<Vec<Box<dyn Extension>>>,
+    headers: String,
+    trailing: Vec<u8>,
+}
+/// What [`TestClient::connect`] returns
+struct Connected {
+    client: TcpStream,
+    /// What the server passed to `after_drop`
+    drops: Receiver<CloseFrame>,
+    /// The status line and headers of the handshake response
+    response: String,
+}
+impl TestClient {
+    fn new(config: WsConfig) -> Self {
+        Self {
+            config,
+            extensions: None,
+            headers: String::new(),
+            trailing: Vec::new(),
+        }
+    }
+    /// Upgrades with our own extensions instead of the ones in the config
+    fn extensions(mut self, extensions: Vec<Box<dyn Extension>>) -> Self {
+        self.extensions = Some(extensions);
+        self
+    }
+    /// Adds a header line to the request, without the trailing `\r\n`
+    fn header(mut self, header: &str) -> Self {
+        self.headers.push_str(header);
+        self.headers.push_str("\r\n");
+        self
+    }
+    /// Bytes sent in the same write as the request, e.g. the first frames
+    fn trailing(mut self, trailing: &[u8]) -> Self {
+        self.trailing.extend_from_slice(trailing);
+        self
+    }
+    async fn connect(self) -> Connected {
+        let TestClient {
+            config,
+            extensions,
+            headers,
+            trailing,
+        } = self;
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (drop_sender, drops) = async_channel::unbounded();
+        task::spawn(async move {
+            let (mut connection, _) = listener.accept().await.unwrap();
+            let request = Request::read_from_stream(&mut connection).await.unwrap();
+            let ws_connection = match extensions {
+                Some(extensions) => {
+                    WsConnection::upgrade_with_extensions(connection, &request, config, extensions)
+                        .await
+                }
+                None => WsConnection::upgrade_from_request(connection, &request, config).await,
+            }
+            .unwrap();
+            let ws_events = WsEvents::new(ws_connection, Hook(drop_sender, None))
+                .await
+                .unwrap();
+            ws_events.run().await.unwrap();
+        });
+
+        let mut request = format!("GET / HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n{}\r\n", headers).into_bytes();
+        request.extend_from_slice(&trailing);
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client.write_all(&request).await.unwrap();
+        let mut response = Vec::new();
+        while !response.ends_with(b"\r\n\r\n") {
+            let mut byte = [0u8; 1];
+            client.read_exact(&mut byte).await.unwrap();
+            response.push(byte[0]);
+        }
+        let response = String::from_utf8(response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 101"));
+        Connected {
+            client,
+            drops,
+            response,
+        }
+    }
+}
+
+async fn read_eof(client: &mut TcpStream) {
+    let mut buffer = [0u8; 1];
+    let read = timeout(Duration::from_secs(5), client.read(&mut buffer))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(read, 0);
+}
+
+async fn read_bytes(client: &mut TcpStream, length: usize) -> Vec<u8> {
+    let mut buffer = vec![0u8; length];
+    timeout(Duration::from_secs(5), client.read_exact(&mut buffer))
+        .await
+        .unwrap()
+        .unwrap();
+    buffer
+}
+
+#[test]
+fn test_ping_is_answered_with_pong() {
+    task::block_on(async {
+        let mut client = TestClient::new(WsConfig::default()).connect().await.client;
+        // FIN(128) + Ping(9), MASK(128) + PayloadLength(2), masking key, "Hi"
+        client
+            .write_all(&[137, 130, 0, 0, 0, 0, 72, 105])
+            .await
+            .unwrap();
+        // FIN(128) + Pong(10), PayloadLength(2), "Hi"
+        assert_eq!(read_bytes(&mut client, 4).await, vec![138, 2, 72, 105]);
+    });
+}
+
+#[test]
+fn test_heartbeat_drops_silent_client() {
+    task::block_on(async {
+        let config = WsConfig::new()
+            .heartbeat(Duration::from_millis(20), 2)
+            .close_timeout(Duration::from_millis(50));
+        let Connected {
+            mut client, drops, ..
+        } = TestClient::new(config).connect().await;
+        // Two pings with an empty payload and then the connection is closed with 1001
+        assert_eq!(read_bytes(&mut client, 2).await, vec![137, 0]);
+        assert_eq!(read_bytes(&mut client, 2).await, vec![137, 0]);
+        let mut close = vec![136, 14, 3, 233];
+        close.extend_from_slice(b"Missed pongs");
+        assert_eq!(read_bytes(&mut client, 16).await, close);
+        read_eof(&mut client).await;
+        // We started the closing handshake, the client never answered
+        assert_eq!(drops.recv().await.unwrap().code, CloseCode::GoingAway);
+    });
+}
+
+#[test]
+fn test_heartbeat_close_is_answered() {
+    task::block_on(async {
+        let config = WsConfig::new()
+            .heartbeat(Duration::from_millis(20), 1)
+            .close_timeout(Duration::from_secs(5));
+        let Connected {
+            mut client, drops, ..
+        } = TestClient::new(config).connect().await;
+        assert_eq!(read_bytes(&mut client, 2).await, vec![137, 0]);
+        let mut close = vec![136, 14, 3, 233];
+        close.extend_from_slice(b"Missed pongs");
+        assert_eq!(read_bytes(&mut client, 16).await, close);
+        // FIN(128) + Close(8), MASK(128) + PayloadLength(2), masking key, 1001
+        client
+            .write_all(&[136, 130, 0, 0, 0, 0, 3, 233])
+            .await
+            .unwrap();
+        // The answer ends the closing handshake we started, so it isn't echoed and we don't wait for the close timeout
+        read_eof(&mut client).await;
+        let close_frame = timeout(Duration::from_secs(1), drops.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(close_frame.code, CloseCode::GoingAway);
+    });
+}
+
+#[test]
+fn test_heartbeat_without_max_missed_pongs_never_drops() {
+    task::block_on(async {
+        let config = WsConfig::new().heartbeat(Duration::from_millis(20), 0);
+        let mut client = TestClient::new(config).connect().await.client;
+        for _ in 0..4 {
+            assert_eq!(read_bytes(&mut client, 2).await, vec![137, 0]);
+        }
+    });
+}
+
+#[test]
+fn test_heartbeat_keeps_answering_client() {
+    task::block_on(async {
+        let config = WsConfig::new().heartbeat(Duration::from_millis(100), 1);
+        let mut client = TestClient::new(config).connect().await.client;
+        for _ in 0..4 {
+            assert_eq!(read_bytes(&mut client, 2).await, vec![137, 0]);
+            // FIN(128) + Pong(10), MASK(128) + PayloadLength(0), masking key
+            client.write_all(&[138, 128, 0, 0, 0, 0]).await.unwrap();
+        }
+    });
+}
+
+#[test]
+fn test_close_is_echoed() {
+    task::block_on(async {
+        let Connected {
+            mut client, drops, ..
+        } = TestClient::new(WsConfig::default()).connect().await;
+        // FIN(128) + Close(8), MASK(128) + PayloadLength(5), masking key, 1000 + "Bye"
+        client
+            .write_all(&[136, 133, 0, 0, 0, 0, 3, 232, 66, 121, 101])
+            .await
+            .unwrap();
+        assert_eq!(
+            read_bytes(&mut client, 7).await,
+            vec![136, 5, 3, 232, 66, 121, 101]
+        );
+        read_eof(&mut client).await;
+        assert_eq!(
+            drops.recv().await.unwrap(),
+            CloseFrame::new(CloseCode::Normal, "Bye")
+        );
+    });
+}
+
+#[test]
+fn test_close_without_status_code() {
+    task::block_on(async {
+        let Connected {
+            mut client, drops, ..
+        } = TestClient::new(WsConfig::default()).connect().await;
+        client.write_all(&[136, 128, 0, 0, 0, 0]).await.unwrap();
+        assert_eq!(read_bytes(&mut client, 2).await, vec![136, 0]);
+        read_eof(&mut client).await;
+        assert_eq!(
+            drops.recv().await.unwrap().code,
+            CloseCode::NoStatusReceived
+        );
+    });
+}
+
+#[test]
+fn test_drop_without_close() {
+    task::block_on(async {
+        let Connected { client, drops, .. } = TestClient::new(WsConfig::default()).connect().await;
+        drop(client);
+        assert_eq!(drops.recv().await.unwrap().code, CloseCode::Abnormal);
+    });
+}
+
+#[test]
+fn test_frame_split_across_writes() {
+    task::block_on(async {
+        let mut client = TestClient::new(WsConfig::default()).connect().await.client;
+        // A ping with its header split in the middle of the masking key
+        client.write_all(&[137, 130, 0, 0]).await.unwrap();
+        client.flush().await.unwrap();
+        task::sleep(Duration::from_millis(20)).await;
+        client.write_all(&[0, 0, 72, 105]).await.unwrap();
+        assert_eq!(read_bytes(&mut client, 4).await, vec![138, 2, 72, 105]);
+    });
+}
+
+#[test]
+fn test_protocol_error_closes_with_1002() {
+    task::block_on(async {
+        let Connected {
+            mut client, drops, ..
+        } = TestClient::new(WsConfig::default()).connect().await;
+        // FIN(128) + Opcode(1) without a MASK
+        client.write_all(&[129, 2, 72, 105]).await.unwrap();
+        // FIN(128) + Close(8), PayloadLength(2), 1002
+        assert_eq!(read_bytes(&mut client, 4).await, vec![136, 2, 3, 234]);
+        read_eof(&mut client).await;
+        assert_eq!(drops.recv().await.unwrap().code, CloseCode::ProtocolError);
+    });
+}
+
+#[test]
+fn test_lenient_accepts_unmasked_frame() {
+    task::block_on(async {
+        let mut client = TestClient::new(WsConfig::new().strict(false))
+            .connect()
+            .await
+            .client;
+        // FIN(128) + Ping(9) without a MASK
+        client.write_all(&[137, 2, 72, 105]).await.unwrap();
+        assert_eq!(read_bytes(&mut client, 4).await, vec![138, 2, 72, 105]);
+    });
+}
+
+#[test]
+fn test_invalid_utf8_closes_with_1007() {
+    task::block_on(async {
+        let Connected {
+            mut client, drops, ..
+        } = TestClient::new(WsConfig::default()).connect().await;
+        // FIN(128) + Opcode(1), MASK(128) + PayloadLength(2), masking key, "H" + an invalid byte
+        client
+            .write_all(&[129, 130, 0, 0, 0, 0, 72, 255])
+            .await
+            .unwrap();
+        // FIN(128) + Close(8), PayloadLength(2), 1007
+        assert_eq!(read_bytes(&mut client, 4).await, vec![136, 2, 3, 239]);
+        read_eof(&mut client).await;
+        assert_eq!(drops.recv().await.unwrap().code, CloseCode::InvalidPayload);
+    });
+}
+
+#[test]
+fn test_frame_over_max_size_closes_with_1009() {
+    task::block_on(async {
+        let Connected {
+            mut client, drops, ..
+        } = TestClient::new(WsConfig::new().max_frame_size(125))
+            .connect()
+            .await;
+        // FIN(128) + Opcode(2), MASK(128) + PayloadLength(127) and a u64 length of 2^40
+        client
+            .write_all(&[130, 255, 0, 0, 1, 0, 0, 0, 0, 0])
+            .await
+            .unwrap();
+        // FIN(128) + Close(8), PayloadLength(2), 1009
+        assert_eq!(read_bytes(&mut client, 4).await, vec![136, 2, 3, 241]);
+        read_eof(&mut client).await;
+        assert_eq!(drops.recv().await.unwrap().code, CloseCode::MessageTooBig);
+    });
+}
+
+/// "Hello" compressed, see RFC 7692 section 7.2.3.1
+const COMPRESSED_HELLO: [u8; 7] = [0xf2, 0x48, 0xcd, 0xc9, 0xc9, 0x07, 0x00];
+
+#[test]
+fn test_deflate_is_negotiated() {
+    task::block_on(async {
+        let config = WsConfig::new().deflate(DeflateConfig::default());
+        let Connected {
+            mut client,
+            response,
+            ..
+        } = TestClient::new(config)
+            .header("Sec-WebSocket-Extensions: permessage-deflate; client_max_window_bits")
+            .connect()
+            .await;
+        assert!(response.contains(
+            "Sec-WebSocket-Extensions: permessage-deflate; client_max_window_bits=15\r\n"
+        ));
+        // FIN(128) + RSV1(64) + Opcode(1), MASK(128) + PayloadLength(7), masking key, compressed "Hello"
+        let mut frame = vec![193, 135, 0, 0, 0, 0];
+        frame.extend_from_slice(&COMPRESSED_HELLO);
+        client.write_all(&frame).await.unwrap();
+        // The echo is compressed as well
+        let mut echo = vec![193, 7];
+        echo.extend_from_slice(&COMPRESSED_HELLO);
+        assert_eq!(read_bytes(&mut client, 9).await, echo);
+    });
+}
+
+#[test]
+fn test_deflate_not_offered() {
+    task::block_on(async {
+        let config = WsConfig::new().deflate(DeflateConfig::default());
+        let Connected {
+            mut client,
+            drops,
+            response,
+        } = TestClient::new(config).connect().await;
+        assert!(!response.contains("Sec-WebSocket-Extensions"));
+        // RSV1 without a negotiated extension is a protocol error
+        let mut frame = vec![193, 135, 0, 0, 0, 0];
+        frame.extend_from_slice(&COMPRESSED_HELLO);
+        client.write_all(&frame).await.unwrap();
+        assert_eq!(read_bytes(&mut client, 4).await, vec![136, 2, 3, 234]);
+        assert_eq!(drops.recv().await.unwrap().code, CloseCode::ProtocolError);
+    });
+}
+
+/// Flips every bit of the payload of frames with RSV3 set
+struct Invert;
+impl Extension for Invert {
+    fn get_name(&self) -> &str {
+        "x-invert"
+    }
+    fn get_rsv(&self) -> u8 {
+        16
+    }
+    fn negotiate(&mut self, offers: &[ExtensionOffer]) -> Option<ExtensionOffer> {
+        offers
+            .iter()
+            .any(|offer| offer.name == "x-invert")
+            .then(|| ExtensionOffer::new("x-invert"))
+    }
+    fn decode(&mut self, mut frame: Frame) -> WsGonzaleResult<Frame> {
+        if frame.rsv & 16 == 16 {
+            frame.rsv &= !16;
+            frame.payload.iter_mut().for_each(|byte| *byte = !*byte);
+        }
+        Ok(frame)
+    }
+    fn encode(&mut self, mut frame: Frame) -> WsGonzaleResult<Frame> {
+        if !Opcode::is_control(frame.opcode) {
+            frame.rsv |= 16;
+            frame.payload.iter_mut().for_each(|byte| *byte = !*byte);
+        }
+        Ok(frame)
+    }
+}
+
+#[test]
+fn test_custom_extension() {
+    task::block_on(async {
+        let extensions: Vec<Box<dyn Extension>> = vec![Box::new(Invert)];
+        let Connected {
+            mut client,
+            response,
+            ..
+        } = TestClient::new(WsConfig::default())
+            .extensions(extensions)
+            .header("Sec-WebSocket-Extensions: x-invert")
+            .connect()
+            .await;
+        assert!(response.contains("Sec-WebSocket-Extensions: x-invert\r\n"));
+        // FIN(128) + RSV3(16) + Opcode(1), MASK(128) + PayloadLength(2), masking key, inverted "Hi"
+        client
+            .write_all(&[145, 130, 0, 0, 0, 0, !72, !105])
+            .await
+            .unwrap();
+        assert_eq!(read_bytes(&mut client, 4).await, vec![145, 2, !72, !105]);
+        // RSV2(32) isn't claimed by any extension
+        client
+            .write_all(&[161, 130, 0, 0, 0, 0, 72, 105])
+            .await
+            .unwrap();
+        assert_eq!(read_bytes(&mut client, 4).await, vec![136, 2, 3, 234]);
+    });
+}
+
+#[test]
+fn test_streamed_message() {
+    task::block_on(async {
+        let mut client = TestClient::new(WsConfig::new().streaming(true))
+            .connect()
+            .await
+            .client;
+        // Opcode(1) without FIN, MASK(128) + PayloadLength(3), masking key, "Hel"
+        client
+            .write_all(&[1, 131, 0, 0, 0, 0, 72, 101, 108])
+            .await
+            .unwrap();
+        // A ping in the middle of the message is answered right away
+        client
+            .write_all(&[137, 130, 0, 0, 0, 0, 72, 105])
+            .await
+            .unwrap();
+        assert_eq!(read_bytes(&mut client, 4).await, vec![138, 2, 72, 105]);
+        // FIN(128) + Continuation(0), MASK(128) + PayloadLength(2), masking key, "lo"
+        client
+            .write_all(&[128, 130, 0, 0, 0, 0, 108, 111])
+            .await
+            .unwrap();
+        // The default on_message_stream passes the whole message to on_message which echoes it
+        assert_eq!(
+            read_bytes(&mut client, 7).await,
+            vec![129, 5, 72, 101, 108, 108, 111]
+        );
+    });
+}
+
+#[test]
+fn test_streamed_invalid_utf8_closes_with_1007() {
+    task::block_on(async {
+        let Connected {
+            mut client, drops, ..
+        } = TestClient::new(WsConfig::new().streaming(true))
+            .connect()
+            .await;
+        // Opcode(1) without FIN, MASK(128) + PayloadLength(2), masking key, "H" + an invalid byte
+        client
+            .write_all(&[1, 130, 0, 0, 0, 0, 72, 255])
+            .await
+            .unwrap();
+        assert_eq!(read_bytes(&mut client, 4).await, vec![136, 2, 3, 239]);
+        read_eof(&mut client).await;
+        assert_eq!(drops.recv().await.unwrap().code, CloseCode::InvalidPayload);
+    });
+}
+
+#[test]
+fn test_streamed_message_over_max_size_closes_with_1009() {
+    task::block_on(async {
+        let config = WsConfig::new().streaming(true).max_message_size(4);
+        let Connected {
+            mut client, drops, ..
+        } = TestClient::new(config).connect().await;
+        // Opcode(1) without FIN, MASK(128) + PayloadLength(3), masking key, "Hel"
+        client
+            .write_all(&[1, 131, 0, 0, 0, 0, 72, 101, 108])
+            .await
+            .unwrap();
+        // Continuation(0) without FIN, MASK(128) + PayloadLength(2), masking key, "lo"
+        client
+            .write_all(&[0, 130, 0, 0, 0, 0, 108, 111])
+            .await
+            .unwrap();
+        assert_eq!(read_bytes(&mut client, 4).await, vec![136, 2, 3, 241]);
+        read_eof(&mut client).await;
+        assert_eq!(drops.recv().await.unwrap().code, CloseCode::MessageTooBig);
+    });
+}
+
+#[test]
+fn test_close_in_streamed_message() {
+    task::block_on(async {
+        let Connected {
+            mut client, drops, ..
+        } = TestClient::new(WsConfig::new().streaming(true))
+            .connect()
+            .await;
+        client
+            .write_all(&[1, 131, 0, 0, 0, 0, 72, 101, 108])
+            .await
+            .unwrap();
+        // FIN(128) + Close(8), MASK(128) + PayloadLength(2), masking key, 1000
+        client
+            .write_all(&[136, 130, 0, 0, 0, 0, 3, 232])
+            .await
+            .unwrap();
+        assert_eq!(read_bytes(&mut client, 4).await, vec![136, 2, 3, 232]);
+        read_eof(&mut client).await;
+        assert_eq!(drops.recv().await.unwrap().code, CloseCode::Normal);
+    });
+}
+
+/// Sends a raw request to a server that tries to upgrade it and returns the response and the upgrade error
+async fn reject(config: WsConfig, request: &'static str) -> (String, WsGonzaleError) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = task::spawn(async move {
+        let (mut connection, _) = listener.accept().await.unwrap();
+        let request = Request::read_from_stream(&mut connection).await.unwrap();
+        WsConnection::upgrade_from_request(connection, &request, config)
+            .await
+            .err()
+            .unwrap()
+    });
+    let mut client = TcpStream::connect(addr).await.unwrap();
+    client.write_all(request.as_bytes()).await.unwrap();
+    let error = server.await;
+    let mut response = String::new();
+    timeout(Duration::from_secs(5), client.read_to_string(&mut response))
+        .await
+        .unwrap()
+        .unwrap();
+    (response, error)
+}
+
+#[test]
+fn test_handshake_without_key_is_rejected() {
+    task::block_on(async {
+        let (response, error) = reject(WsConfig::default(), "GET / HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Version: 13\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+        assert_eq!(
+            error,
+            WsGonzaleError::InvalidHandshake(HandshakeError::InvalidKey)
+        );
+    });
+}
+
+#[test]
+fn test_handshake_with_unsupported_version_is_rejected() {
+    task::block_on(async {
+        let (response, error) = reject(WsConfig::default(), "GET / HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 8\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 426 Upgrade Required\r\n"));
+        assert!(response.contains("\r\nSec-WebSocket-Version: 13\r\n"));
+        assert_eq!(
+            error,
+            WsGonzaleError::InvalidHandshake(HandshakeError::UnsupportedVersion)
+        );
+    });
+}
+
+#[test]
+fn test_frame_in_same_write_as_request() {
+    task::block_on(async {
+        // FIN(128) + Text(1), MASK(128) + PayloadLength(2), masking key, "Hi"
+        let mut client = TestClient::new(WsConfig::default())
+            .trailing(&[129, 130, 0, 0, 0, 0, 72, 105])
+            .connect()
+            .await
+            .client;
+        // The echo of the text message
+        assert_eq!(read_bytes(&mut client, 4).await, vec![129, 2, 72, 105]);
+    });
+}
+
+#[test]
+fn test_subprotocol_is_negotiated() {
+    task::block_on(async {
+        let config = WsConfig::new().subprotocols(&["chat.v2", "chat.v1"]);
+        let response = TestClient::new(config)
+            .header("Sec-WebSocket-Protocol: chat.v1, chat.v2")
+            .connect()
+            .await
+            .response;
+        assert!(response.contains("\r\nSec-WebSocket-Protocol: chat.v2\r\n"));
+        let response = TestClient::new(WsConfig::default())
+            .header("Sec-WebSocket-Protocol: chat.v1")
+            .connect()
+            .await
+            .response;
+        assert!(!response.contains("Sec-WebSocket-Protocol"));
+    });
+}
+
+#[test]
+fn test_unsupported_subprotocol_is_rejected() {
+    task::block_on(async {
+        let config = WsConfig::new().subprotocols(&["graphql-transport-ws"]);
+        let (response, error) = reject(config, "GET / HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\nSec-WebSocket-Protocol: chat.v1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+        assert_eq!(
+            error,
+            WsGonzaleError::InvalidHandshake(HandshakeError::UnsupportedSubprotocol)
+        );
+    });
+}
+
+#[test]
+fn test_invalid_extensions_are_rejected() {
+    task::block_on(async {
+        let config = WsConfig::new().deflate(DeflateConfig::default());
+        let (response, error) = reject(config, "GET / HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\nSec-WebSocket-Extensions: permessage-deflate; client_max_window_bits=\"1 0\"\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+        assert_eq!(
+            error,
+            WsGonzaleError::InvalidHandshake(HandshakeError::InvalidExtensions)
+        );
+    });
+}
+
+/// Upgrades a request with a handshake callback and returns the response and the data it was accepted with
+async fn upgrade_with_callback(
+    callback: fn(&Request) -> HandshakeDecision<u32>,
+) -> (String, WsGonzaleResult<u32>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = task::spawn(async move {
+        let (mut connection, _) = listener.accept().await.unwrap();
+        let request = Request::read_from_stream(&mut connection).await.unwrap();
+        WsConnection::upgrade_with_callback(connection, &request, WsConfig::default(), callback)
+            .await
+            .map(|(_, data)| data)
+    });
+    let mut client = TcpStream::connect(addr).await.unwrap();
+    client.write_all(b"GET /chat HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\nAuthorization: Bearer 42\r\n\r\n").await.unwrap();
+    let data = server.await;
+    let mut response = String::new();
+    timeout(Duration::from_secs(5), client.read_to_string(&mut response))
+        .await
+        .unwrap()
+        .unwrap();
+    (response, data)
+}
+
+#[test]
+fn test_handshake_callback_accepts() {
+    task::block_on(async {
+        let (response, data) = upgrade_with_callback(|request| {
+            let user_id = request.get_headers().get("Authorization").unwrap()[7..]
+                .parse()
+                .unwrap();
+            HandshakeDecision::accept(user_id).with_header("Set-Cookie", "session=abc")
+        })
+        .await;
+        assert!(response.starts_with("HTTP/1.1 101 Switching Protocols\r\n"));
+        assert!(response.contains("\r\nSet-Cookie: session=abc\r\n"));
+        assert_eq!(data, Ok(42));
+    });
+}
+
+#[test]
+fn test_handshake_callback_rejects() {
+    task::block_on(async {
+        let (response, data) = upgrade_with_callback(|_| {
+            HandshakeDecision::reject(Response::new(401, "Unauthorized").with_body("Log in first"))
+        })
+        .await;
+        assert_eq!(
+            response,
+            "HTTP/1.1 401 Unauthorized\r\nContent-Length: 12\r\n\r\nLog in first"
+        );
+        assert_eq!(data, Err(WsGonzaleError::HandshakeRejected(401)));
+    });
+}
+
+#[test]
+fn test_forbidden_origin_is_rejected() {
+    task::block_on(async {
+        let config =
+            WsConfig::new().origin_policy(OriginPolicy::allow_list(&["https://*.example.com"]));
+        let (response, error) = reject(config, "GET / HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\nOrigin: https://evil.com\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 403 Forbidden\r\n"));
+        assert_eq!(
+            error,
+            WsGonzaleError::InvalidHandshake(HandshakeError::ForbiddenOrigin)
+        );
+        let config =
+            WsConfig::new().origin_policy(OriginPolicy::allow_list(&["https://*.example.com"]));
+        let response = TestClient::new(config)
+            .header("Origin: https://chat.example.com")
+            .connect()
+            .await
+            .response;
+        assert!(response.starts_with("HTTP/1.1 101"));
+    });
+}
+
+#[test]
+fn test_origin_policy_without_request_is_rejected() {
+    task::block_on(async {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = task::spawn(async move {
+            let (connection, _) = listener.accept().await.unwrap();
+            let config =
+                WsConfig::new().origin_policy(OriginPolicy::allow_list(&["https://example.com"]));
+            #[allow(deprecated)]
+            let upgrade =
+                WsConnection::upgrade_with_config(connection, "dGhlIHNhbXBsZSBub25jZQ==", config)
+                    .await;
+            upgrade.err()
+        });
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        let mut response = String::new();
+        timeout(Duration::from_secs(5), client.read_to_string(&mut response))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(response.starts_with("HTTP/1.1 403 Forbidden\r\n"));
+        assert_eq!(
+            server.await,
+            Some(WsGonzaleError::InvalidHandshake(
+                HandshakeError::ForbiddenOrigin
+            ))
+        );
+    });
+}
+
+#[test]
+fn test_invalid_key_without_request_is_rejected() {
+    task::block_on(async {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = task::spawn(async move {
+            let (connection, _) = listener.accept().await.unwrap();
+            #[allow(deprecated)]
+            let upgrade = WsConnection::upgrade(connection, "not a key").await;
+            upgrade.err()
+        });
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        let mut response = String::new();
+        timeout(Duration::from_secs(5), client.read_to_string(&mut response))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+        // The reason is kept instead of turning into an io::Error
+        assert_eq!(
+            server.await,
+            Some(WsGonzaleError::InvalidHandshake(HandshakeError::InvalidKey))
+        );
+    });
+}
+
+#[test]
+fn test_key_with_whitespace_is_trimmed_before_hashing() {
+    task::block_on(async {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        task::spawn(async move {
+            let (connection, _) = listener.accept().await.unwrap();
+            #[allow(deprecated)]
+            let _ = WsConnection::upgrade(connection, " dGhlIHNhbXBsZSBub25jZQ== ").await;
+        });
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        let mut response = String::new();
+        timeout(Duration::from_secs(5), client.read_to_string(&mut response))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(response.starts_with("HTTP/1.1 101 Switching Protocols\r\n"));
+        assert!(response.contains("\r\nSec-Websocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+    });
+}
+
+/// Serves one connection with `router` and returns everything the server wrote back to `request`
+async fn route(router: Router, request: &str) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    task::spawn(async move {
+        let (connection, _) = listener.accept().await.unwrap();
+        let _ = router.handle(connection, WsConfig::new()).await;
+    });
+    let mut client = TcpStream::connect(addr).await.unwrap();
+    client.write_all(request.as_bytes()).await.unwrap();
+    let mut response = String::new();
+    timeout(Duration::from_secs(5), client.read_to_string(&mut response))
+        .await
+        .unwrap()
+        .unwrap();
+    response
+}
+
+#[test]
+fn test_router_dispatches_by_method_and_path() {
+    task::block_on(async {
+        let router = || {
+            Router::new()
+                .route(HTTPMethod::GET, "/rooms/:id", |_, params| async move {
+                    Response::new(200, "OK").with_body(params.get("id").unwrap())
+                })
+                .route(HTTPMethod::POST, "/notify", |request, _| async move {
+                    let body = request.get_body().map(|body| body.get_body().to_string());
+                    Response::new(202, "Accepted").with_body(&body.unwrap_or_default())
+                })
+                .route(HTTPMethod::DELETE, "/rooms/:id", |_, _| async {
+                    Response::new(204, "No Content")
+                })
+                .route(HTTPMethod::Unknown, "/rooms/:id", |_, _| async {
+                    Response::new(200, "OK")
+                })
+                .websocket("/chat", |_| {
+                    HandshakeDecision::accept(Hook(async_channel::unbounded().0, None))
+                })
+        };
+        let response = route(router(), "GET /rooms/42?token=abc HTTP/1.1\r\n\r\n").await;
+        assert_eq!(response, "HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\n42");
+        let response = route(
+            router(),
+            "POST /notify HTTP/1.1\r\nContent-Length: 5\r\n\r\nHello",
+        )
+        .await;
+        assert_eq!(
+            response,
+            "HTTP/1.1 202 Accepted\r\nContent-Length: 5\r\n\r\nHello"
+        );
+        let response = route(router(), "DELETE /rooms/42 HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 204 No Content\r\n"));
+        // Wrong method, unknown path and a path that can't be decoded
+        let response = route(router(), "POST /rooms/42 HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 405 Method Not Allowed\r\nAllow: GET, DELETE\r\n"));
+        let response = route(router(), "PROPFIND /rooms/42 HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"));
+        let response = route(router(), "POST /chat HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 405 Method Not Allowed\r\nAllow: GET\r\n"));
+        let response = route(router(), "GET /chat HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 426 Upgrade Required\r\nUpgrade: websocket\r\n"));
+        let response = route(router(), "GET /metrics HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+        let response = route(router(), "GET /rooms/%ff HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+        // A request that can't be read gets a status line too
+        let response = route(
+            router(),
+            "POST /notify HTTP/1.1\r\nContent-Length: eleven\r\n\r\n",
+        )
+        .await;
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+        let response = route(
+            router(),
+            "POST /notify HTTP/1.1\r\nContent-Length: 2000000\r\n\r\n",
+        )
+        .await;
+        assert!(response.starts_with("HTTP/1.1 413 Content Too Large\r\n"));
+        let router = router().fallback(|_, _| async { Response::new(410, "Gone") });
+        let response = route(router, "GET /metrics HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 410 Gone\r\n"));
+    });
+}
+
+#[test]
+fn test_router_upgrades_websocket_routes() {
+    task::block_on(async {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (drop_sender, drop_receiver) = async_channel::unbounded();
+        let (room_sender, room_receiver) = async_channel::unbounded();
+        let router = Router::new().websocket("/rooms/:id", move |upgrade| {
+            let id = upgrade.get_params().get("id").unwrap().to_string();
+            let subprotocol = upgrade.get_subprotocol().map(str::to_string);
+            let _ = room_sender.try_send((id, subprotocol));
+            HandshakeDecision::accept(Hook(drop_sender.clone(), None))
+        });
+        task::spawn(async move {
+            let (connection, _) = listener.accept().await.unwrap();
+            let config = WsConfig::new().subprotocols(&["chat"]);
+            router.handle(connection, config).await.unwrap();
+        });
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client.write_all(b"GET /rooms/42 HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\nSec-WebSocket-Protocol: chat\r\n\r\n").await.unwrap();
+        let response = read_bytes(&mut client, 12).await;
+        assert_eq!(response, b"HTTP/1.1 101");
+        // The hook was made before the handshake was answered
+        assert_eq!(
+            room_receiver.try_recv().unwrap(),
+            ("42".to_string(), Some("chat".to_string()))
+        );
+        // Masked close frame with status code 1000
+        client
+            .write_all(&[0x88, 0x82, 0, 0, 0, 0, 0x03, 0xe8])
+            .await
+            .unwrap();
+        let close_frame = timeout(Duration::from_secs(5), drop_receiver.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(close_frame.code, CloseCode::Normal);
+    });
+}
+
+#[test]
+fn test_router_websocket_route_rejects_handshake() {
+    task::block_on(async {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let router = Router::new().websocket("/rooms/:id", |upgrade| {
+            match upgrade.get_params().get("id") {
+                Some("42") => HandshakeDecision::accept(Hook(async_channel::unbounded().0, None)),
+                _ => HandshakeDecision::reject(Response::new(403, "Forbidden")),
+            }
+        });
+        let server = task::spawn(async move {
+            let (connection, _) = listener.accept().await.unwrap();
+            router.handle(connection, WsConfig::new()).await.err()
+        });
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client.write_all(b"GET /rooms/7 HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n").await.unwrap();
+        let mut response = String::new();
+        timeout(Duration::from_secs(5), client.read_to_string(&mut response))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(response.starts_with("HTTP/1.1 403 Forbidden\r\n"));
+        assert_eq!(server.await, Some(WsGonzaleError::HandshakeRejected(403)));
+    });
+}
+
+/// Reads a request sent by a client that stops writing after `request`, with everything the server answered
+async fn read_request(
+    config: WsConfig,
+    request: &'static [u8],
+) -> (WsGonzaleResult<Request>, String) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = task::spawn(async move {
+        let (mut connection, _) = listener.accept().await.unwrap();
+        Request::read_from_stream_with_config(&mut connection, &config).await
+    });
+    let mut client = TcpStream::connect(addr).await.unwrap();
+    client.write_all(request).await.unwrap();
+    client.shutdown(std::net::Shutdown::Write).unwrap();
+    let mut response = String::new();
+    timeout(Duration::from_secs(5), client.read_to_string(&mut response))
+        .await
+        .unwrap()
+        .unwrap();
+    (server.await, response)
+}
+
+#[test]
+fn test_request_limits_from_config() {
+    task::block_on(async {
+        let request = b"POST /notify HTTP/1.1\r\nContent-Length: 5\r\n\r\nHello";
+        let (result, response) = read_request(WsConfig::new(), request).await;
+        assert!(result.is_ok());
+        assert_eq!(response, "");
+        let config = WsConfig::new().max_body_size(4);
+        let (result, response) = read_request(config, request).await;
+        assert_eq!(result.err(), Some(WsGonzaleError::RequestTooLarge));
+        assert!(response.starts_with("HTTP/1.1 413 Content Too Large\r\n"));
+        let config = WsConfig::new().max_header_size(16);
+        let (result, response) = read_request(config, request).await;
+        assert_eq!(result.err(), Some(WsGonzaleError::HeadersTooLarge));
+        assert!(response.starts_with("HTTP/1.1 431 Request Header Fields Too Large\r\n"));
+        // The client hangs up in the middle of the headers, there's nobody to answer
+        let (result, response) = read_request(WsConfig::new(), b"GET / HTTP/1.1\r\n").await;
+        assert_eq!(result.err(), Some(WsGonzaleError::ConnectionClosed));
+        assert_eq!(response, "");
+    });
+}
+
+#[test]
+fn test_malformed_request_is_answered_with_400() {
+    task::block_on(async {
+        let requests: [&'static [u8]; 3] = [
+            b"GET / HTTP/1.1\r\nHost: \xff\r\n\r\n",
+            b"POST / HTTP/1.1\r\nContent-Length: eleven\r\n\r\n",
+            b"POST / HTTP/1.1\r\nContent-Length: 1\r\n\r\n\xff",
+        ];
+        for request in requests.iter() {
+            let (result, response) = read_request(WsConfig::new(), request).await;
+            assert_eq!(result.err(), Some(WsGonzaleError::InvalidPayload));
+            assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+        }
+    });
+}